@@ -1,68 +1,441 @@
-use flate2::{write::GzEncoder, Compression};
+use flate2::{Compression, write::GzEncoder};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use miette::{Context, IntoDiagnostic, Result};
-use oci_spec::image::{Descriptor, Digest};
+use oci_spec::image::{Descriptor, Digest, MediaType};
 use sha2::{Digest as _, Sha256};
+use std::collections::HashMap;
 use std::io::Write;
-use tracing::info;
+use std::path::Path;
+use tracing::{info, warn};
 
-fn tar_folder(src_path: impl AsRef<std::path::Path>) -> Result<Vec<u8>> {
-    let buf = Vec::new();
-    let mut tar = tar::Builder::new(buf);
+use crate::recipe::{LayerCompression, LayerOptions, Owner};
+
+/// Split the destination path inside the image into its components.
+fn dest_components(dest: &str) -> Result<Vec<&str>> {
+    let components: Vec<&str> = dest
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    if components.contains(&"..") {
+        miette::bail!("destination {dest:?} must not contain `..`");
+    }
+    Ok(components)
+}
+
+/// Write the folder as a tar archive to `writer`, returning the writer when done.
+fn tar_folder<W: Write>(
+    src_path: impl AsRef<Path>,
+    options: &LayerOptions,
+    writer: W,
+) -> Result<W> {
+    let mut tar = tar::Builder::new(writer);
     tar.follow_symlinks(false);
     tar.sparse(false);
     tar.mode(tar::HeaderMode::Deterministic);
-    tar.append_dir_all("", src_path).into_diagnostic()?;
+
+    let components = dest_components(&options.dest)?;
+    let src_path = src_path.as_ref();
+    let mut filter = PathFilter::new(src_path, options)?;
+    // The parents of the destination are owned by root unless a `chown` rule says otherwise.
+    // They are held back like any other directory, so a layer without entries stays empty
+    let mut pending_dirs = Vec::new();
+    if options.parents {
+        let meta = std::fs::metadata(src_path)
+            .into_diagnostic()
+            .with_context(|| format!("reading {src_path:?}"))?;
+        for depth in 1..components.len() {
+            let path = components[..depth].join("/");
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata_in_mode(&meta, tar::HeaderMode::Deterministic);
+            header.set_size(0);
+            apply_overrides(&mut header, &path, options, Owner { uid: 0, gid: 0 });
+            pending_dirs.push((format!("{path}/"), header));
+        }
+    }
+    append_tree(
+        &mut tar,
+        src_path,
+        &components.join("/"),
+        "",
+        options,
+        &mut filter,
+        &mut pending_dirs,
+    )?;
+    if filter.excluded > 0 {
+        info!("excluded {} entries from {src_path:?}", filter.excluded);
+    }
     tar.into_inner().into_diagnostic()
 }
 
-fn gzip(input: Vec<u8>) -> Result<Vec<u8>> {
-    let buf = Vec::new();
-    let mut encoder = GzEncoder::new(buf, Compression::fast());
-    encoder.write_all(&input).into_diagnostic()?;
-    encoder.finish().into_diagnostic()
+/// Name of the file in the layer folder listing additional exclude globs.
+const IGNORE_FILE: &str = ".kltignore";
+
+/// Decides which entries of the layer folder end up in the layer.
+struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    excluded: usize,
+}
+
+impl PathFilter {
+    fn new(src: &Path, options: &LayerOptions) -> Result<Self> {
+        let include = if options.include.is_empty() {
+            None
+        } else {
+            Some(build_globset(&options.include)?)
+        };
+
+        let mut exclude = options.exclude.clone();
+        let ignore_file = src.join(IGNORE_FILE);
+        if ignore_file.is_file() {
+            let contents = std::fs::read_to_string(&ignore_file)
+                .into_diagnostic()
+                .with_context(|| format!("reading {ignore_file:?}"))?;
+            exclude.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_owned),
+            );
+            exclude.push(IGNORE_FILE.to_owned());
+        }
+
+        Ok(PathFilter {
+            include,
+            exclude: build_globset(&exclude)?,
+            excluded: 0,
+        })
+    }
+
+    /// Whether the entry at `rel`, relative to the layer folder, is left out.
+    /// Excluding a directory leaves out everything below it.
+    fn excludes(&mut self, rel: &str, is_dir: bool) -> bool {
+        let excluded = self.exclude.is_match(rel)
+            || (!is_dir
+                && self
+                    .include
+                    .as_ref()
+                    .is_some_and(|include| !include.is_match(rel)));
+        if excluded {
+            self.excluded += 1;
+        }
+        excluded
+    }
+}
+
+/// Build a set of gitignore-like globs: a pattern without a `/` matches at any depth,
+/// otherwise it is anchored at the layer folder.
+fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let trimmed = pattern.trim_end_matches('/');
+        let anchored = match trimmed.strip_prefix('/') {
+            Some(anchored) => anchored.to_owned(),
+            None if trimmed.contains('/') => trimmed.to_owned(),
+            None => format!("**/{trimmed}"),
+        };
+        let glob = GlobBuilder::new(&anchored)
+            .literal_separator(true)
+            .build()
+            .into_diagnostic()
+            .with_context(|| format!("invalid glob {pattern:?}"))?;
+        builder.add(glob);
+    }
+    builder.build().into_diagnostic()
+}
+
+/// Recursively append `src`, found at `rel` in the layer folder, to the archive at `path`,
+/// visiting directory entries in sorted order so that the resulting layer is reproducible.
+/// With `include` globs, directories are held back in `pending_dirs` until something below
+/// them is added, so that directories without included files are left out.
+fn append_tree(
+    tar: &mut tar::Builder<impl Write>,
+    src: &Path,
+    path: &str,
+    rel: &str,
+    options: &LayerOptions,
+    filter: &mut PathFilter,
+    pending_dirs: &mut Vec<(String, tar::Header)>,
+) -> Result<()> {
+    let meta = std::fs::symlink_metadata(src)
+        .into_diagnostic()
+        .with_context(|| format!("reading {src:?}"))?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&meta, tar::HeaderMode::Deterministic);
+    let owner = Owner {
+        uid: options.uid,
+        gid: options.gid,
+    };
+    apply_overrides(&mut header, path, options, owner);
+
+    let file_type = meta.file_type();
+    if file_type.is_dir() {
+        if !path.is_empty() {
+            header.set_size(0);
+            pending_dirs.push((format!("{path}/"), header));
+            if filter.include.is_none() {
+                append_pending_dirs(tar, pending_dirs)?;
+            }
+        }
+        let mut children = std::fs::read_dir(src)
+            .into_diagnostic()?
+            .map(|entry| entry.and_then(|e| Ok((e.file_name(), e.file_type()?.is_dir()))))
+            .collect::<std::io::Result<Vec<_>>>()
+            .into_diagnostic()?;
+        children.sort();
+        let join = |parent: &str, name: &str| {
+            if parent.is_empty() {
+                name.to_owned()
+            } else {
+                format!("{parent}/{name}")
+            }
+        };
+        for (name, is_dir) in children {
+            let name_str = name
+                .to_str()
+                .ok_or_else(|| miette::miette!("file name {name:?} is not valid UTF-8"))?;
+            let child_rel = join(rel, name_str);
+            if filter.excludes(&child_rel, is_dir) {
+                continue;
+            }
+            append_tree(
+                tar,
+                &src.join(&name),
+                &join(path, name_str),
+                &child_rel,
+                options,
+                filter,
+                pending_dirs,
+            )?;
+        }
+        // Nothing below the directory was added
+        if pending_dirs
+            .last()
+            .is_some_and(|(pending, _)| pending.trim_end_matches('/') == path)
+        {
+            pending_dirs.pop();
+        }
+    } else if file_type.is_symlink() {
+        append_pending_dirs(tar, pending_dirs)?;
+        let target = std::fs::read_link(src).into_diagnostic()?;
+        header.set_size(0);
+        tar.append_link(&mut header, path, target)
+            .into_diagnostic()?;
+    } else if file_type.is_file() {
+        append_pending_dirs(tar, pending_dirs)?;
+        let file = std::fs::File::open(src)
+            .into_diagnostic()
+            .with_context(|| format!("opening {src:?}"))?;
+        tar.append_data(&mut header, path, file).into_diagnostic()?;
+    } else {
+        warn!("skipping {src:?}, which is neither a file, directory nor symlink");
+    }
+    Ok(())
+}
+
+/// Append the directories held back by [`append_tree`], parents first.
+fn append_pending_dirs(
+    tar: &mut tar::Builder<impl Write>,
+    pending_dirs: &mut Vec<(String, tar::Header)>,
+) -> Result<()> {
+    for (path, mut header) in pending_dirs.drain(..) {
+        tar.append_data(&mut header, path, std::io::empty())
+            .into_diagnostic()?;
+    }
+    Ok(())
+}
+
+/// Apply the ownership and permission rules from the options to the entry at `path`,
+/// falling back to `default_owner` if no `chown` rule matches.
+/// Owners are inherited from parent directories, modes only apply to the path itself,
+/// so that a directory mode doesn't make the files below it executable.
+fn apply_overrides(
+    header: &mut tar::Header,
+    path: &str,
+    options: &LayerOptions,
+    default_owner: Owner,
+) {
+    let owner = most_specific_rule(&options.chown, path)
+        .copied()
+        .unwrap_or(default_owner);
+    header.set_uid(owner.uid);
+    header.set_gid(owner.gid);
+    if let Some((_, mode)) = options
+        .chmod
+        .iter()
+        .find(|(rule, _)| rule.trim_matches('/') == path)
+    {
+        header.set_mode(mode.0);
+    }
+}
+
+/// Find the rule for the longest image path that is `path` or one of its parents.
+fn most_specific_rule<'a, T>(rules: &'a HashMap<String, T>, path: &str) -> Option<&'a T> {
+    rules
+        .iter()
+        .map(|(rule, value)| (rule.trim_matches('/'), value))
+        .filter(|(rule, _)| {
+            rule.is_empty()
+                || path == *rule
+                || path
+                    .strip_prefix(rule)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .max_by_key(|(rule, _)| rule.len())
+        .map(|(_, value)| value)
+}
+
+/// Compresses everything written to it according to the layer compression.
+enum LayerEncoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    None(W),
+}
+
+impl<W: Write> LayerEncoder<W> {
+    fn new(inner: W, compression: LayerCompression) -> std::io::Result<Self> {
+        Ok(match compression {
+            LayerCompression::Gzip { level } => {
+                LayerEncoder::Gzip(GzEncoder::new(inner, Compression::new(level)))
+            }
+            LayerCompression::Zstd { level } => {
+                LayerEncoder::Zstd(zstd::Encoder::new(inner, level)?)
+            }
+            LayerCompression::None => LayerEncoder::None(inner),
+        })
+    }
+
+    /// Write the end of the compressed stream and return the inner writer.
+    fn finish(self) -> std::io::Result<W> {
+        match self {
+            LayerEncoder::Gzip(encoder) => encoder.finish(),
+            LayerEncoder::Zstd(encoder) => encoder.finish(),
+            LayerEncoder::None(inner) => Ok(inner),
+        }
+    }
+}
+
+impl<W: Write> Write for LayerEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            LayerEncoder::Gzip(encoder) => encoder.write(buf),
+            LayerEncoder::Zstd(encoder) => encoder.write(buf),
+            LayerEncoder::None(inner) => inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            LayerEncoder::Gzip(encoder) => encoder.flush(),
+            LayerEncoder::Zstd(encoder) => encoder.flush(),
+            LayerEncoder::None(inner) => inner.flush(),
+        }
+    }
+}
+
+fn media_type(compression: LayerCompression) -> MediaType {
+    match compression {
+        LayerCompression::Gzip { .. } => MediaType::ImageLayerGzip,
+        LayerCompression::Zstd { .. } => MediaType::ImageLayerZstd,
+        LayerCompression::None => MediaType::ImageLayer,
+    }
+}
+
+/// Passes everything written through to the inner writer while hashing and counting it.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// Return the inner writer along with the digest and size of everything written.
+    pub fn finish(self) -> (W, Digest, u64) {
+        (self.inner, finalize_sha256(self.hasher), self.size)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 pub struct AppLayer {
-    pub contents: Vec<u8>,
+    /// The compressed layer, spooled to a temporary file that is removed on drop.
+    pub blob: tempfile::TempPath,
     pub descriptor: Descriptor,
     pub diff_id: Digest,
     pub created_by: String,
 }
 
 impl AppLayer {
+    /// Tar, compress and hash the folder in one pass, spooling the layer to a temporary
+    /// file so that its size and digest are known before it is uploaded.
     #[tracing::instrument(skip_all)]
-    pub async fn build_from_directory(input_folder: &str) -> Result<AppLayer> {
+    pub async fn build_from_directory(
+        input_folder: &str,
+        options: &LayerOptions,
+        compression: LayerCompression,
+    ) -> Result<AppLayer> {
         let input_folder = std::path::Path::new(input_folder).to_owned();
+        let options = options.clone();
 
         let thread_span = tracing::debug_span!("thread").or_current();
         tokio::task::spawn_blocking(move || {
             let _entered = thread_span.entered();
 
-            info!("building app layer from {input_folder:?}");
-            let contents_plain =
-                tar_folder(&input_folder).with_context(|| format!("tarring {input_folder:?}"))?;
-            let plain_len = contents_plain.len();
-            let plain_digest = sha256_digest(&contents_plain);
-            info!("App Layer uncompressed size: {plain_len} bytes");
+            let dest = &options.dest;
+            info!("building app layer from {input_folder:?} at {dest}");
+            let spool = tempfile::NamedTempFile::new()
+                .into_diagnostic()
+                .with_context(|| "creating temporary file for app layer")?;
+            let compressed = HashingWriter::new(std::io::BufWriter::new(spool));
+            let encoder = LayerEncoder::new(compressed, compression)
+                .into_diagnostic()
+                .with_context(|| format!("setting up {compression} compression"))?;
+            let plain = tar_folder(&input_folder, &options, HashingWriter::new(encoder))
+                .with_context(|| format!("tarring {input_folder:?}"))?;
 
-            let contents = gzip(contents_plain).with_context(|| "gzipping tarred contents")?;
-            let layer_digest = sha256_digest(&contents);
-            let layer_size = contents.len();
+            let (encoder, plain_digest, plain_len) = plain.finish();
+            info!("App Layer uncompressed size: {plain_len} bytes");
+            let compressed = encoder
+                .finish()
+                .into_diagnostic()
+                .with_context(|| format!("compressing tarred contents with {compression}"))?;
+            let (spool, layer_digest, layer_size) = compressed.finish();
+            let spool = spool
+                .into_inner()
+                .map_err(|e| e.into_error())
+                .into_diagnostic()
+                .with_context(|| "writing app layer to temporary file")?;
             info!(
                 "App Layer compressed size: {layer_size} bytes ({:.2}%)",
                 layer_size as f32 / plain_len as f32 * 100.0
             );
-            let descriptor = Descriptor::new(
-                oci_spec::image::MediaType::ImageLayerGzip,
-                layer_size as u64,
-                layer_digest,
-            );
+            let descriptor = Descriptor::new(media_type(compression), layer_size, layer_digest);
 
             Ok(AppLayer {
-                contents,
+                blob: spool.into_temp_path(),
                 descriptor,
                 diff_id: plain_digest,
-                created_by: format!("KLT COPY {}/* /", input_folder.to_str().unwrap()),
+                created_by: format!("KLT COPY {}/* {dest}", input_folder.to_str().unwrap()),
             })
         })
         .await
@@ -72,8 +445,12 @@ impl AppLayer {
 }
 
 pub fn sha256_digest(bytes: &[u8]) -> Digest {
-    let digest = Sha256::digest(bytes);
-    let digest_str = base16ct::lower::encode_string(&digest);
+    finalize_sha256(Sha256::new_with_prefix(bytes))
+}
+
+/// Turn a hasher that has been fed incrementally into a digest.
+pub fn finalize_sha256(hasher: Sha256) -> Digest {
+    let digest_str = base16ct::lower::encode_string(&hasher.finalize());
     Digest::try_from(format!("sha256:{digest_str}")).expect("should be valid sha256 digest")
 }
 
@@ -96,15 +473,29 @@ mod tests {
     }
 
     #[test]
-    fn test_gzip() -> miette::Result<()> {
+    fn test_gzip() -> std::io::Result<()> {
         let input = b"test data".repeat(1000).to_vec();
-        let compressed = gzip(input.clone())?;
+        let mut encoder = LayerEncoder::new(Vec::new(), LayerCompression::default())?;
+        encoder.write_all(&input)?;
+        let compressed = encoder.finish()?;
         assert!(!compressed.is_empty());
         assert!(compressed.len() < input.len() * 2); // Compressed size should be reasonable
         assert_eq!(&compressed[0..2], [0x1f, 0x8b]); // gzip magic number
         Ok(())
     }
 
+    #[test]
+    fn test_hashing_writer() -> std::io::Result<()> {
+        let mut writer = HashingWriter::new(Vec::new());
+        writer.write_all(b"test ")?;
+        writer.write_all(b"data")?;
+        let (inner, digest, size) = writer.finish();
+        assert_eq!(inner, b"test data");
+        assert_eq!(digest, sha256_digest(b"test data"));
+        assert_eq!(size, 9);
+        Ok(())
+    }
+
     #[test]
     fn test_tar_folder() -> std::io::Result<()> {
         let temp_dir = TempDir::new()?;
@@ -112,7 +503,7 @@ mod tests {
         let mut test_file = fs::File::create(test_file_path)?;
         test_file.write_all(b"test content")?;
 
-        let tarred = tar_folder(temp_dir.path()).unwrap();
+        let tarred = tar_folder(temp_dir.path(), &LayerOptions::default(), Vec::new()).unwrap();
         assert!(!tarred.is_empty());
 
         // Basic validation of tar format
@@ -120,6 +511,166 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_tar_folder_with_dest() -> std::io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir(temp_dir.path().join("bin"))?;
+        fs::write(temp_dir.path().join("bin/app"), b"test content")?;
+
+        let options = LayerOptions {
+            dest: "/opt/app/".to_string(),
+            ..Default::default()
+        };
+        let tarred = tar_folder(temp_dir.path(), &options, Vec::new()).unwrap();
+        let mut archive = tar::Archive::new(tarred.as_slice());
+        let paths = archive
+            .entries()?
+            .map(|e| e.unwrap().path().unwrap().to_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec!["opt/", "opt/app/", "opt/app/bin/", "opt/app/bin/app"]
+        );
+
+        let options = LayerOptions {
+            dest: "/opt/../etc".to_string(),
+            ..Default::default()
+        };
+        assert!(tar_folder(temp_dir.path(), &options, Vec::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tar_folder_ownership_and_modes() -> std::io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir_all(temp_dir.path().join("data/cache"))?;
+        fs::write(temp_dir.path().join("data/cache/entry"), b"cached")?;
+        fs::write(temp_dir.path().join("server"), b"binary")?;
+        fs::write(temp_dir.path().join("data/config"), b"config")?;
+
+        let options = LayerOptions {
+            dest: "/app".to_string(),
+            uid: 1000,
+            gid: 1000,
+            chown: HashMap::from([("/app/data/cache".to_string(), "0:0".parse().unwrap())]),
+            chmod: HashMap::from([
+                ("/app/data".to_string(), "0700".parse().unwrap()),
+                ("/app/data/cache/".to_string(), "0750".parse().unwrap()),
+            ]),
+            ..Default::default()
+        };
+        let tarred = tar_folder(temp_dir.path(), &options, Vec::new()).unwrap();
+        let mut archive = tar::Archive::new(tarred.as_slice());
+        let headers = archive
+            .entries()?
+            .map(|e| {
+                let e = e.unwrap();
+                let header = e.header();
+                (
+                    e.path().unwrap().to_str().unwrap().to_owned(),
+                    (
+                        header.uid().unwrap(),
+                        header.gid().unwrap(),
+                        header.mode().unwrap(),
+                    ),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(headers["app/"], (1000, 1000, 0o755));
+        assert_eq!(headers["app/server"], (1000, 1000, 0o644));
+        assert_eq!(headers["app/data/"], (1000, 1000, 0o700));
+        assert_eq!(headers["app/data/cache/"], (0, 0, 0o750));
+        // Owners are inherited, modes are not
+        assert_eq!(headers["app/data/cache/entry"], (0, 0, 0o644));
+        assert_eq!(headers["app/data/config"], (1000, 1000, 0o644));
+
+        // Parents of the destination are owned by root, unless a `chown` rule covers them
+        let options = LayerOptions {
+            dest: "/usr/local/bin".to_string(),
+            uid: 1000,
+            gid: 1000,
+            chown: HashMap::from([("/usr/local".to_string(), "1000".parse().unwrap())]),
+            ..Default::default()
+        };
+        let tarred = tar_folder(temp_dir.path(), &options, Vec::new()).unwrap();
+        let mut archive = tar::Archive::new(tarred.as_slice());
+        let headers = archive
+            .entries()?
+            .take(3)
+            .map(|e| {
+                let e = e.unwrap();
+                let header = e.header();
+                (
+                    e.path().unwrap().to_str().unwrap().to_owned(),
+                    (
+                        header.uid().unwrap(),
+                        header.gid().unwrap(),
+                        header.mode().unwrap(),
+                    ),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headers,
+            vec![
+                ("usr/".to_string(), (0, 0, 0o755)),
+                ("usr/local/".to_string(), (1000, 1000, 0o755)),
+                ("usr/local/bin/".to_string(), (1000, 1000, 0o755)),
+            ]
+        );
+
+        // Without parents, they keep their owner from the base image
+        let options = LayerOptions {
+            parents: false,
+            ..options
+        };
+        let tarred = tar_folder(temp_dir.path(), &options, Vec::new()).unwrap();
+        let mut archive = tar::Archive::new(tarred.as_slice());
+        let first = archive.entries()?.next().unwrap()?;
+        assert_eq!(first.path()?.to_str(), Some("usr/local/bin/"));
+        assert_eq!(first.header().uid()?, 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tar_folder_filters() -> std::io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir_all(temp_dir.path().join("bin"))?;
+        fs::create_dir_all(temp_dir.path().join("tests/fixtures"))?;
+        fs::write(temp_dir.path().join("bin/app"), b"binary")?;
+        fs::write(temp_dir.path().join("bin/app.pdb"), b"symbols")?;
+        fs::write(temp_dir.path().join("bin/.DS_Store"), b"")?;
+        fs::write(temp_dir.path().join("tests/fixtures/input"), b"")?;
+        fs::write(temp_dir.path().join("README.md"), b"readme")?;
+        fs::write(
+            temp_dir.path().join(IGNORE_FILE),
+            b"# editor junk\n.DS_Store\n",
+        )?;
+
+        let entries = |options: &LayerOptions| -> Vec<String> {
+            let tarred = tar_folder(temp_dir.path(), options, Vec::new()).unwrap();
+            tar::Archive::new(tarred.as_slice())
+                .entries()
+                .unwrap()
+                .map(|e| e.unwrap().path().unwrap().to_str().unwrap().to_owned())
+                .collect()
+        };
+
+        let options = LayerOptions {
+            exclude: vec!["*.pdb".to_string(), "/tests/".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(entries(&options), vec!["README.md", "bin/", "bin/app"]);
+
+        let options = LayerOptions {
+            include: vec!["bin/*".to_string()],
+            ..Default::default()
+        };
+        // Directories without included files are left out
+        assert_eq!(entries(&options), vec!["bin/", "bin/app", "bin/app.pdb"]);
+        Ok(())
+    }
+
     #[test(tokio::test)]
     async fn test_app_layer_build() -> std::io::Result<()> {
         let temp_dir = TempDir::new()?;
@@ -128,11 +679,17 @@ mod tests {
         let mut test_file = fs::File::create(test_file_path)?;
         test_file.write_all(test_content)?;
 
-        let app_layer = AppLayer::build_from_directory(temp_dir.path().to_str().unwrap())
-            .await
-            .unwrap();
+        let app_layer = AppLayer::build_from_directory(
+            temp_dir.path().to_str().unwrap(),
+            &LayerOptions::default(),
+            LayerCompression::default(),
+        )
+        .await
+        .unwrap();
 
-        assert!(!app_layer.contents.is_empty());
+        let contents = fs::read(&app_layer.blob)?;
+        assert_eq!(contents.len() as u64, app_layer.descriptor.size());
+        assert_eq!(&sha256_digest(&contents), app_layer.descriptor.digest());
         assert_eq!(
             app_layer.descriptor.media_type(),
             &oci_spec::image::MediaType::ImageLayerGzip
@@ -140,6 +697,36 @@ mod tests {
         assert!(app_layer.created_by.contains("KLT COPY"));
         assert_ne!(app_layer.descriptor.digest(), &app_layer.diff_id);
 
+        let zstd_layer = AppLayer::build_from_directory(
+            temp_dir.path().to_str().unwrap(),
+            &LayerOptions::default(),
+            LayerCompression::Zstd { level: 19 },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            zstd_layer.descriptor.media_type(),
+            &oci_spec::image::MediaType::ImageLayerZstd
+        );
+        let contents = fs::read(&zstd_layer.blob)?;
+        assert_eq!(&contents[0..4], [0x28, 0xb5, 0x2f, 0xfd]); // zstd magic number
+        assert_eq!(zstd_layer.diff_id, app_layer.diff_id);
+        let decompressed = zstd::decode_all(contents.as_slice())?;
+        assert_eq!(sha256_digest(&decompressed), zstd_layer.diff_id);
+
+        let plain_layer = AppLayer::build_from_directory(
+            temp_dir.path().to_str().unwrap(),
+            &LayerOptions::default(),
+            LayerCompression::None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            plain_layer.descriptor.media_type(),
+            &oci_spec::image::MediaType::ImageLayer
+        );
+        assert_eq!(plain_layer.descriptor.digest(), &plain_layer.diff_id);
+
         Ok(())
     }
 }
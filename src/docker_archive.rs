@@ -0,0 +1,465 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::StreamExt;
+use miette::{Context, IntoDiagnostic, Result};
+use oci_spec::image::{
+    Descriptor, Digest, ImageConfiguration, ImageManifest, ImageManifestBuilder, MediaType,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::SyncIoBridge;
+use tracing::{debug, info, warn};
+
+use crate::app_layer::{finalize_sha256, sha256_digest};
+use crate::recipe::TagName;
+use crate::registry_client::BlobStream;
+
+/// An entry of the `manifest.json` read by `docker load`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifestEntry {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+struct ArchiveState {
+    builder: Option<tar::Builder<File>>,
+    written: HashSet<Digest>,
+}
+
+/// A tarball in the format written by `docker save` and read by `docker load`.
+///
+/// Blobs are stored under `blobs/<algorithm>/<hex>` as the archive is written,
+/// the manifest is added when the image is finished.
+pub struct DockerArchive {
+    path: PathBuf,
+    name: String,
+    state: Arc<Mutex<ArchiveState>>,
+}
+
+impl DockerArchive {
+    /// Create the archive file; `name` is the repository name used for the tags.
+    pub fn create(path: impl AsRef<Path>, name: impl ToString) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let file = File::create(&path)
+            .into_diagnostic()
+            .with_context(|| format!("creating docker archive {}", path.display()))?;
+        let mut builder = tar::Builder::new(file);
+        builder.mode(tar::HeaderMode::Deterministic);
+        Ok(Self {
+            path,
+            name: name.to_string(),
+            state: Arc::new(Mutex::new(ArchiveState {
+                builder: Some(builder),
+                written: HashSet::new(),
+            })),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn blob_path(digest: &Digest) -> String {
+        format!("blobs/{}/{}", digest.algorithm(), digest.digest())
+    }
+
+    pub fn has_blob(&self, digest: &Digest) -> bool {
+        self.state.lock().unwrap().written.contains(digest)
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn write_blob(&self, digest: &Digest, contents: Vec<u8>) -> Result<()> {
+        self.append_blob(digest, move || {
+            Ok((contents.len() as u64, std::io::Cursor::new(contents)))
+        })
+        .await
+    }
+
+    /// Copy a blob from a file, e.g. an app layer spooled to disk.
+    #[tracing::instrument(skip_all)]
+    pub async fn write_blob_file(&self, digest: &Digest, source: &Path) -> Result<()> {
+        let source = source.to_owned();
+        self.append_blob(digest, move || {
+            let file = File::open(&source)?;
+            Ok((file.metadata()?.len(), file))
+        })
+        .await
+    }
+
+    /// Copy a blob from a stream of chunks.
+    #[tracing::instrument(skip_all)]
+    pub async fn write_blob_stream(
+        &self,
+        digest: &Digest,
+        size: u64,
+        stream: BlobStream,
+    ) -> Result<()> {
+        // The tar builder is synchronous, so the stream is read from the blocking task
+        let reader = SyncIoBridge::new(tokio_util::io::StreamReader::new(stream));
+        self.append_blob(digest, move || Ok((size, reader))).await
+    }
+
+    /// Append a blob unless it was already written, `open` providing its size and contents.
+    async fn append_blob<R: Read>(
+        &self,
+        digest: &Digest,
+        open: impl FnOnce() -> std::io::Result<(u64, R)> + Send + 'static,
+    ) -> Result<()> {
+        let state = self.state.clone();
+        let digest = digest.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut state = state.lock().unwrap();
+            if state.written.contains(&digest) {
+                return Ok(());
+            }
+            debug!("adding blob {digest} to docker archive");
+            let builder = state
+                .builder
+                .as_mut()
+                .ok_or_else(|| miette::miette!("docker archive is already finished"))?;
+            let (size, contents) = open()
+                .into_diagnostic()
+                .with_context(|| format!("reading blob {digest}"))?;
+            append_file(builder, &Self::blob_path(&digest), size, contents)
+                .with_context(|| format!("adding blob {digest} to docker archive"))?;
+            state.written.insert(digest);
+            Ok(())
+        })
+        .await
+        .into_diagnostic()?
+    }
+
+    /// Write `manifest.json` for the image and finish the archive.
+    #[tracing::instrument(skip_all)]
+    pub async fn finish(&self, manifest: &ImageManifest, tags: &[TagName]) -> Result<()> {
+        let repo_tags = tags
+            .iter()
+            .map(|tag| format!("{}:{tag}", self.name))
+            .collect::<Vec<_>>();
+        let archive_manifest = vec![ArchiveManifestEntry {
+            config: Self::blob_path(manifest.config().digest()),
+            repo_tags: Some(repo_tags),
+            layers: manifest
+                .layers()
+                .iter()
+                .map(|layer| Self::blob_path(layer.digest()))
+                .collect(),
+        }];
+        let manifest_json = serde_json::to_vec(&archive_manifest).into_diagnostic()?;
+
+        info!("finishing docker archive {}", self.path.display());
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut builder = state
+                .lock()
+                .unwrap()
+                .builder
+                .take()
+                .ok_or_else(|| miette::miette!("docker archive is already finished"))?;
+            append_file(
+                &mut builder,
+                "manifest.json",
+                manifest_json.len() as u64,
+                manifest_json.as_slice(),
+            )?;
+            builder.into_inner().into_diagnostic()?;
+            Ok(())
+        })
+        .await
+        .into_diagnostic()?
+        .context("finishing docker archive")
+    }
+}
+
+/// Location of a file's contents within the archive.
+#[derive(Clone, Copy, Debug)]
+struct EntryPosition {
+    offset: u64,
+    size: u64,
+}
+
+/// A tarball written by `docker save`, read as a base image.
+///
+/// Files referenced by the archive's `manifest.json` are hashed when opening the
+/// archive, so that layers can be described by digest like registry blobs.
+#[derive(Clone)]
+pub struct DockerArchiveReader {
+    path: PathBuf,
+    blobs: Arc<HashMap<Digest, EntryPosition>>,
+    manifest: Arc<ImageManifest>,
+    configuration: Arc<ImageConfiguration>,
+}
+
+impl DockerArchiveReader {
+    #[tracing::instrument(skip_all)]
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        info!("reading docker archive {}", path.display());
+        tokio::task::spawn_blocking(move || {
+            Self::read_archive(&path).with_context(|| format!("reading {}", path.display()))
+        })
+        .await
+        .into_diagnostic()?
+    }
+
+    fn read_archive(path: &Path) -> Result<Self> {
+        let mut entries = HashMap::new();
+        let mut archive = tar::Archive::new(File::open(path).into_diagnostic()?);
+        for entry in archive.entries().into_diagnostic()? {
+            let entry = entry.into_diagnostic()?;
+            let name = entry.path().into_diagnostic()?;
+            let name = name.strip_prefix("./").unwrap_or(&name).to_owned();
+            entries.insert(
+                name,
+                EntryPosition {
+                    offset: entry.raw_file_position(),
+                    size: entry.size(),
+                },
+            );
+        }
+        let mut file = archive.into_inner();
+        let lookup = |name: &str| {
+            entries
+                .get(Path::new(name))
+                .copied()
+                .ok_or_else(|| miette::miette!("{name} is missing from the archive"))
+        };
+
+        let manifest_json = read_entry(&mut file, lookup("manifest.json")?)?;
+        let archive_manifest: Vec<ArchiveManifestEntry> =
+            serde_json::from_slice(&manifest_json).into_diagnostic()?;
+        let image = archive_manifest
+            .first()
+            .ok_or_else(|| miette::miette!("the archive contains no images"))?;
+        if archive_manifest.len() > 1 {
+            warn!(
+                "the archive contains {} images, using the first one",
+                archive_manifest.len()
+            );
+        }
+
+        let mut blobs = HashMap::new();
+
+        let config_position = lookup(&image.config)?;
+        let config_bytes = read_entry(&mut file, config_position)?;
+        let config_digest = sha256_digest(&config_bytes);
+        let configuration: ImageConfiguration =
+            serde_json::from_slice(&config_bytes).into_diagnostic()?;
+        blobs.insert(config_digest.clone(), config_position);
+
+        let mut layers = Vec::new();
+        for layer in &image.layers {
+            let position = lookup(layer)?;
+            let (digest, media_type) = hash_layer_entry(&mut file, position)
+                .with_context(|| format!("hashing {layer}"))?;
+            debug!("layer {layer} has digest {digest}");
+            layers.push(Descriptor::new(media_type, position.size, digest.clone()));
+            blobs.insert(digest, position);
+        }
+
+        let manifest = ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .media_type(MediaType::ImageManifest)
+            .config(Descriptor::new(
+                MediaType::ImageConfig,
+                config_position.size,
+                config_digest,
+            ))
+            .layers(layers)
+            .build()
+            .into_diagnostic()?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            blobs: Arc::new(blobs),
+            manifest: Arc::new(manifest),
+            configuration: Arc::new(configuration),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The manifest and configuration of the image in the archive.
+    pub fn image(&self) -> (ImageManifest, ImageConfiguration) {
+        (
+            ImageManifest::clone(&self.manifest),
+            ImageConfiguration::clone(&self.configuration),
+        )
+    }
+
+    #[tracing::instrument(skip_all)]
+    /// Read a blob as a stream of chunks.
+    pub async fn read_blob_stream(&self, digest: &Digest) -> Result<BlobStream> {
+        let position = self.position(digest)?;
+        let mut file = tokio::fs::File::open(&self.path).await.into_diagnostic()?;
+        file.seek(SeekFrom::Start(position.offset))
+            .await
+            .into_diagnostic()?;
+        Ok(tokio_util::io::ReaderStream::new(file.take(position.size)).boxed())
+    }
+
+    fn position(&self, digest: &Digest) -> Result<EntryPosition> {
+        self.blobs
+            .get(digest)
+            .copied()
+            .ok_or_else(|| miette::miette!("blob {digest} is not in {}", self.path.display()))
+    }
+
+    pub async fn read_blob(&self, digest: &Digest) -> Result<bytes::Bytes> {
+        let position = self.position(digest)?;
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut file = File::open(&path).into_diagnostic()?;
+            read_entry(&mut file, position).map(bytes::Bytes::from)
+        })
+        .await
+        .into_diagnostic()?
+    }
+}
+
+fn read_entry(file: &mut File, position: EntryPosition) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(position.offset))
+        .into_diagnostic()?;
+    let mut contents = Vec::with_capacity(position.size as usize);
+    file.take(position.size)
+        .read_to_end(&mut contents)
+        .into_diagnostic()?;
+    Ok(contents)
+}
+
+/// Hash a layer without loading it into memory, detecting its compression from the magic bytes.
+fn hash_layer_entry(file: &mut File, position: EntryPosition) -> Result<(Digest, MediaType)> {
+    file.seek(SeekFrom::Start(position.offset))
+        .into_diagnostic()?;
+    let mut reader = file.take(position.size);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut media_type = None;
+    loop {
+        let n = reader.read(&mut buf).into_diagnostic()?;
+        if n == 0 {
+            break;
+        }
+        media_type.get_or_insert_with(|| match buf[..n] {
+            [0x1f, 0x8b, ..] => MediaType::ImageLayerGzip,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => MediaType::ImageLayerZstd,
+            _ => MediaType::ImageLayer,
+        });
+        hasher.update(&buf[..n]);
+    }
+    Ok((
+        finalize_sha256(hasher),
+        media_type.unwrap_or(MediaType::ImageLayer),
+    ))
+}
+
+fn append_file(
+    builder: &mut tar::Builder<File>,
+    path: &str,
+    size: u64,
+    contents: impl Read,
+) -> Result<()> {
+    let mut header = tar::Header::new_ustar();
+    header.set_size(size);
+    header.set_mode(0o644);
+    builder
+        .append_data(&mut header, path, contents)
+        .into_diagnostic()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_layer::sha256_digest;
+    use oci_spec::image::{Descriptor, ImageManifestBuilder, MediaType};
+    use std::io::Read;
+    use tempfile::TempDir;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn test_docker_archive() -> Result<()> {
+        let dir = TempDir::new().into_diagnostic()?;
+        let path = dir.path().join("image.tar");
+        let archive = DockerArchive::create(&path, "registry/repo")?;
+
+        let layer = b"layer".to_vec();
+        let layer_digest = sha256_digest(&layer);
+        let config =
+            br#"{"architecture":"amd64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#
+                .to_vec();
+        let config_digest = sha256_digest(&config);
+        archive.write_blob(&layer_digest, layer.clone()).await?;
+        // Writing the same blob twice only adds it once
+        archive.write_blob(&layer_digest, layer.clone()).await?;
+        archive.write_blob(&config_digest, config.clone()).await?;
+        assert!(archive.has_blob(&layer_digest));
+
+        let manifest = ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(Descriptor::new(
+                MediaType::ImageConfig,
+                config.len() as u64,
+                config_digest.clone(),
+            ))
+            .layers(vec![Descriptor::new(
+                MediaType::ImageLayerGzip,
+                5,
+                layer_digest.clone(),
+            )])
+            .build()
+            .into_diagnostic()?;
+        archive
+            .finish(&manifest, &[TagName::try_from("latest").unwrap()])
+            .await?;
+
+        let mut entries = HashMap::new();
+        let mut tar = tar::Archive::new(File::open(&path).into_diagnostic()?);
+        for entry in tar.entries().into_diagnostic()? {
+            let mut entry = entry.into_diagnostic()?;
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).into_diagnostic()?;
+            entries.insert(
+                entry.path().into_diagnostic()?.to_str().unwrap().to_owned(),
+                contents,
+            );
+        }
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[&format!("blobs/sha256/{}", layer_digest.digest())],
+            layer
+        );
+
+        let archive_manifest: serde_json::Value =
+            serde_json::from_slice(&entries["manifest.json"]).into_diagnostic()?;
+        assert_eq!(
+            archive_manifest,
+            serde_json::json!([{
+                "Config": format!("blobs/sha256/{}", config_digest.digest()),
+                "RepoTags": ["registry/repo:latest"],
+                "Layers": [format!("blobs/sha256/{}", layer_digest.digest())],
+            }])
+        );
+
+        let reader = DockerArchiveReader::open(&path).await?;
+        let (read_manifest, _) = reader.image();
+        assert_eq!(read_manifest.config().digest(), &config_digest);
+        assert_eq!(read_manifest.layers().len(), 1);
+        assert_eq!(read_manifest.layers()[0].digest(), &layer_digest);
+        assert_eq!(
+            read_manifest.layers()[0].media_type(),
+            &MediaType::ImageLayer
+        );
+        assert_eq!(reader.read_blob(&layer_digest).await?.as_ref(), layer);
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use base64::Engine;
+use miette::{Context, IntoDiagnostic, Result};
+use secrecy::SecretString;
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::recipe::Authorization;
+
+/// The parts of the Docker CLI's `config.json` that hold registry credentials.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    creds_store: Option<String>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AuthEntry {
+    /// Base64 of `username:password`.
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    identitytoken: Option<String>,
+}
+
+/// The output of `docker-credential-<helper> get`.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Username credential helpers report for identity tokens.
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+/// Look up credentials for the registry like `docker login` stored them, in
+/// `$DOCKER_CONFIG/config.json` or `~/.docker/config.json`.
+/// Returns [`Authorization::None`] if there are none.
+pub async fn credentials_for(registry: &str) -> Result<Authorization> {
+    let Some(path) = config_path() else {
+        return Ok(Authorization::None);
+    };
+    let registry = registry.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let Some(config) = DockerConfig::load(&path)? else {
+            return Ok(Authorization::None);
+        };
+        let auth = config.lookup(&registry)?;
+        if !matches!(auth, Authorization::None) {
+            info!("using credentials for {registry} from {}", path.display());
+        }
+        Ok(auth)
+    })
+    .await
+    .into_diagnostic()?
+}
+
+fn config_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".docker"),
+    };
+    Some(dir.join("config.json"))
+}
+
+impl DockerConfig {
+    fn load(path: &Path) -> Result<Option<Self>> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .into_diagnostic()
+                    .with_context(|| format!("reading {}", path.display()));
+            }
+        };
+        serde_json::from_slice(&contents)
+            .into_diagnostic()
+            .with_context(|| format!("parsing {}", path.display()))
+            .map(Some)
+    }
+
+    /// Find credentials for the registry: a registry-specific credential helper wins over the
+    /// default credential store, which wins over the `auths` entries.
+    fn lookup(&self, registry: &str) -> Result<Authorization> {
+        let registry = normalize_registry(registry);
+        let helper = self
+            .cred_helpers
+            .iter()
+            .find(|(key, _)| normalize_registry(key) == registry)
+            .map(|(_, helper)| helper)
+            .or(self.creds_store.as_ref());
+        if let Some(helper) = helper {
+            match run_helper(helper, registry) {
+                Ok(Some(credentials)) => return Ok(credentials.into()),
+                Ok(None) => debug!("credential helper {helper} has no credentials for {registry}"),
+                Err(e) => warn!(
+                    "{:?}",
+                    e.context(format!("running credential helper {helper}"))
+                ),
+            }
+        }
+
+        self.auths
+            .iter()
+            .find(|(key, _)| normalize_registry(key) == registry)
+            .map(|(key, entry)| {
+                entry
+                    .to_authorization()
+                    .with_context(|| format!("reading credentials for {key}"))
+            })
+            .unwrap_or(Ok(Authorization::None))
+    }
+}
+
+impl AuthEntry {
+    fn to_authorization(&self) -> Result<Authorization> {
+        if let Some(token) = &self.identitytoken {
+            return Ok(Authorization::IdentityToken(SecretString::from(
+                token.as_str(),
+            )));
+        }
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            return Ok(Authorization::UserPassword(
+                username.clone(),
+                SecretString::from(password.as_str()),
+            ));
+        }
+        let Some(auth) = &self.auth else {
+            return Ok(Authorization::None);
+        };
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(auth)
+            .into_diagnostic()?;
+        let decoded = String::from_utf8(decoded).into_diagnostic()?;
+        let (username, password) = decoded
+            .split_once(':')
+            .ok_or_else(|| miette::miette!("auth should be base64 of username:password"))?;
+        Ok(Authorization::UserPassword(
+            username.to_owned(),
+            SecretString::from(password),
+        ))
+    }
+}
+
+impl From<HelperCredentials> for Authorization {
+    fn from(credentials: HelperCredentials) -> Self {
+        if credentials.username == IDENTITY_TOKEN_USERNAME {
+            Authorization::IdentityToken(SecretString::from(credentials.secret))
+        } else {
+            Authorization::UserPassword(
+                credentials.username,
+                SecretString::from(credentials.secret),
+            )
+        }
+    }
+}
+
+/// Reduce the keys used in `config.json` to a registry host, mapping Docker Hub's aliases
+/// to `docker.io`.
+fn normalize_registry(key: &str) -> &str {
+    let host = key
+        .strip_prefix("https://")
+        .or_else(|| key.strip_prefix("http://"))
+        .unwrap_or(key);
+    let host = host.split('/').next().unwrap_or(host);
+    match host {
+        "index.docker.io" | "registry-1.docker.io" => "docker.io",
+        host => host,
+    }
+}
+
+/// Ask `docker-credential-<helper>` for the registry's credentials.
+fn run_helper(helper: &str, registry: &str) -> Result<Option<HelperCredentials>> {
+    // Docker Hub credentials are stored under its legacy URL
+    let server_url = match registry {
+        "docker.io" => "https://index.docker.io/v1/",
+        registry => registry,
+    };
+    let mut child = Command::new(format!("docker-credential-{helper}"))
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .into_diagnostic()?;
+    child
+        .stdin
+        .take()
+        .expect("stdin should be piped")
+        .write_all(server_url.as_bytes())
+        .into_diagnostic()?;
+    let output = child.wait_with_output().into_diagnostic()?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stdout);
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+        miette::bail!(
+            "failed with {}: {}{}",
+            output.status,
+            message.trim(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout)
+        .into_diagnostic()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn user_password(auth: Authorization) -> (String, String) {
+        match auth {
+            Authorization::UserPassword(user, pass) => (user, pass.expose_secret().to_owned()),
+            _ => panic!("Expected UserPassword"),
+        }
+    }
+
+    #[test]
+    fn test_lookup_auths() {
+        let config: DockerConfig = serde_json::from_value(serde_json::json!({
+            "auths": {
+                "https://index.docker.io/v1/": { "auth": "dXNlcjpwYXNz" },
+                "ghcr.io": { "username": "octocat", "password": "ghp_secret" },
+                "registry.example.com": { "identitytoken": "refresh" }
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            user_password(config.lookup("index.docker.io").unwrap()),
+            ("user".to_string(), "pass".to_string())
+        );
+        assert_eq!(
+            user_password(config.lookup("ghcr.io").unwrap()),
+            ("octocat".to_string(), "ghp_secret".to_string())
+        );
+        assert!(matches!(
+            config.lookup("registry.example.com").unwrap(),
+            Authorization::IdentityToken(token) if token.expose_secret() == "refresh"
+        ));
+        assert!(matches!(
+            config.lookup("quay.io").unwrap(),
+            Authorization::None
+        ));
+    }
+
+    #[test]
+    fn test_lookup_credential_helper() {
+        let bin_dir = tempfile::TempDir::new().unwrap();
+        let helper = bin_dir.path().join("docker-credential-test");
+        std::fs::write(
+            &helper,
+            r#"#!/bin/sh
+read server
+if [ "$server" = "ghcr.io" ]; then
+  echo '{"ServerURL":"ghcr.io","Username":"helper-user","Secret":"helper-secret"}'
+else
+  echo "credentials not found in native keychain"
+  exit 1
+fi
+"#,
+        )
+        .unwrap();
+        std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config: DockerConfig = serde_json::from_value(serde_json::json!({
+            "auths": {
+                "ghcr.io": { "auth": "dXNlcjpwYXNz" },
+                "quay.io": { "auth": "dXNlcjpwYXNz" }
+            },
+            "credHelpers": { "ghcr.io": "test", "quay.io": "test" }
+        }))
+        .unwrap();
+
+        let path = std::env::join_paths(
+            std::iter::once(bin_dir.path().to_owned())
+                .chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+        )
+        .unwrap();
+        temp_env::with_var("PATH", Some(path), || {
+            assert_eq!(
+                user_password(config.lookup("ghcr.io").unwrap()),
+                ("helper-user".to_string(), "helper-secret".to_string())
+            );
+            // Falls back to auths when the helper has nothing
+            assert_eq!(
+                user_password(config.lookup("quay.io").unwrap()),
+                ("user".to_string(), "pass".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn test_normalize_registry() {
+        assert_eq!(
+            normalize_registry("https://index.docker.io/v1/"),
+            "docker.io"
+        );
+        assert_eq!(normalize_registry("registry-1.docker.io"), "docker.io");
+        assert_eq!(normalize_registry("https://ghcr.io"), "ghcr.io");
+        assert_eq!(normalize_registry("localhost:5000"), "localhost:5000");
+    }
+}
@@ -1,87 +1,376 @@
 use futures::TryFutureExt;
-use miette::{Context, Result};
-use oci_spec::image::{Arch, Digest, ImageConfiguration, ImageManifest, Os};
+use miette::{Context, IntoDiagnostic, Result};
+use oci_spec::image::{
+    Descriptor, Digest, ImageConfiguration, ImageIndex, ImageIndexBuilder, ImageManifest, MediaType,
+};
+use std::time::Instant;
+use tokio::sync::Semaphore;
 use tracing::{debug, info};
 
+mod provider;
+mod report;
+mod sink;
 mod state;
 
-use crate::app_layer::AppLayer;
-use crate::recipe::Recipe;
+use crate::app_layer::{AppLayer, sha256_digest};
+use crate::docker_archive::{DockerArchive, DockerArchiveReader};
+use crate::docker_config;
+use crate::lockfile::Lockfile;
+use crate::oci_layout::ImageLayout;
+use crate::recipe::{Authorization, BaseImage, Platform, Recipe};
 use crate::registry_client::{ClientScope, RegistryClient};
-use state::PreparationState;
+use provider::BlobProvider;
+use report::{BuildReport, Timings};
+use sink::ImageSink;
+pub use sink::Output;
+use state::{ImageSummary, PreparationState};
 
-/// Build and push an OCI image from a recipe.
+/// How many blobs are transferred to the target at the same time unless configured otherwise.
+const DEFAULT_PARALLEL_TRANSFERS: usize = 5;
+
+/// Build an OCI image from a recipe and write it to the output.
+/// A registry base image is pinned to the digest in the lockfile, or pinned there.
 #[tracing::instrument(skip_all)]
-pub async fn build_image(recipe: &Recipe) -> Result<Digest> {
+pub async fn build_image(
+    recipe: &Recipe,
+    output: &Output,
+    lock: &mut Lockfile,
+) -> Result<BuildReport> {
     debug!("{:?}", &recipe);
 
-    let base_client = create_base_client(recipe).await?;
-    let (base_manifest, base_config, app_layer, target_client) =
-        pull_base_and_build_app_layer(recipe, &base_client).await?;
-
-    let image = assemble_image(recipe, base_manifest, base_config, base_client, app_layer);
-
-    debug!("{:?}", &image.manifest());
+    let started = Instant::now();
+    let platforms = recipe.target.platforms();
+    let ((base_digest, images), sink) = tokio::try_join!(
+        build_platform_images(recipe, &platforms, lock),
+        create_sink(recipe, output)
+    )?;
+    let prepared = Instant::now();
 
-    let digest = image
-        .push_to(&target_client, recipe.target.tags())
-        .await
-        .with_context(|| "pushing image")?;
+    // Shared between the platforms, so that the limit holds for the whole push
+    let transfers = Semaphore::new(
+        recipe
+            .target
+            .max_parallel_transfers
+            .unwrap_or(DEFAULT_PARALLEL_TRANSFERS)
+            .max(1),
+    );
+    let (digest, summaries) = if recipe.target.is_multi_platform() {
+        push_index(recipe, images, &platforms, &sink, &transfers).await
+    } else {
+        let image = images.into_iter().next().unwrap();
+        image
+            .push_to(&sink, recipe.target.tags(), &transfers)
+            .await
+            .map(|summary| (summary.manifest_descriptor.digest().clone(), vec![summary]))
+    }
+    .with_context(|| "pushing image")?;
 
     info!(
-        "successfully pushed image to {}/{}:{:?}",
-        target_client.registry,
-        target_client.repo,
+        "successfully pushed image to {sink}:{:?}",
         recipe.target.tags()
     );
 
-    Ok(digest)
+    let timings = Timings {
+        prepare_seconds: prepared - started,
+        push_seconds: prepared.elapsed(),
+        total_seconds: started.elapsed(),
+    };
+    Ok(BuildReport::new(
+        recipe,
+        base_digest,
+        digest,
+        platforms.into_iter().zip(summaries),
+        timings,
+        false,
+    ))
 }
 
-/// Create the base registry client.
-async fn create_base_client(recipe: &Recipe) -> Result<RegistryClient> {
-    RegistryClient::new(
-        &recipe.base.image.resolve_registry(),
-        &recipe.base.image.repository(),
-        &recipe.base.auth,
-        ClientScope::Pull,
-    )
-    .await
-    .context("creating base image registry client")
+/// Build the image like [`build_image`], but only print its manifests and configurations
+/// and which blobs would be transferred, without writing anything to the output.
+#[tracing::instrument(skip_all)]
+pub async fn dry_run(recipe: &Recipe, output: &Output, lock: &mut Lockfile) -> Result<BuildReport> {
+    debug!("{:?}", &recipe);
+
+    let started = Instant::now();
+    let platforms = recipe.target.platforms();
+    let ((base_digest, images), sink) = tokio::try_join!(
+        build_platform_images(recipe, &platforms, lock),
+        open_existing_sink(recipe, output)
+    )?;
+    let prepared = Instant::now();
+
+    let mut plans = Vec::new();
+    for (image, platform) in images.into_iter().zip(&platforms) {
+        let mut plan = image.plan(sink.as_ref()).await?;
+        print_plan(&plan, platform)?;
+        plan.manifest_descriptor
+            .set_platform(Some(platform.to_oci()));
+        plans.push(plan);
+    }
+
+    let digest = if recipe.target.is_multi_platform() {
+        let manifests = plans
+            .iter()
+            .map(|plan| plan.manifest_descriptor.clone())
+            .collect();
+        let (body, descriptor) = index_to_blob(recipe, manifests)?;
+        println!("index {}:\n{body}", descriptor.digest());
+        descriptor.digest().clone()
+    } else {
+        plans[0].manifest_descriptor.digest().clone()
+    };
+    let tags = recipe
+        .target
+        .tags()
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    println!("would tag {digest} as {}", tags.join(", "));
+
+    let timings = Timings {
+        prepare_seconds: prepared - started,
+        push_seconds: prepared.elapsed(),
+        total_seconds: started.elapsed(),
+    };
+    Ok(BuildReport::new(
+        recipe,
+        base_digest,
+        digest,
+        platforms.into_iter().zip(plans),
+        timings,
+        true,
+    ))
+}
+
+fn print_plan(plan: &ImageSummary, platform: &Platform) -> Result<()> {
+    println!(
+        "{platform} manifest {}:\n{}",
+        plan.manifest_descriptor.digest(),
+        plan.manifest.to_string_pretty().into_diagnostic()?
+    );
+    println!(
+        "{platform} config {}:\n{}",
+        plan.manifest.config().digest(),
+        plan.configuration.to_string_pretty().into_diagnostic()?
+    );
+    println!("{platform} blobs:");
+    for (descriptor, action, _) in &plan.blobs {
+        println!(
+            "  {action:<6} {} ({} bytes)",
+            descriptor.digest(),
+            descriptor.size()
+        );
+    }
+    Ok(())
+}
+
+/// Create the sink the image is written to.
+async fn create_sink(recipe: &Recipe, output: &Output) -> Result<ImageSink> {
+    match output {
+        Output::Registry => RegistryClient::new(
+            &recipe.target.registry,
+            &recipe.target.repo,
+            &registry_auth(&recipe.target.auth, &recipe.target.registry).await?,
+            ClientScope::Push {
+                mount_from: mount_source(recipe),
+            },
+            &recipe.target.transport,
+        )
+        .await
+        .context("creating target registry client")
+        .map(|client| ImageSink::Registry(client.with_chunk_size(recipe.target.upload_chunk_size))),
+        Output::OciLayout(path) => ImageLayout::create(path)
+            .await
+            .context("creating target image layout")
+            .map(ImageSink::OciLayout),
+        Output::DockerArchive(path) => {
+            if recipe.target.is_multi_platform() {
+                return Err(sink::single_platform_only());
+            }
+            let name = format!("{}/{}", recipe.target.registry, recipe.target.repo);
+            DockerArchive::create(path, name)
+                .context("creating target docker archive")
+                .map(ImageSink::DockerArchive)
+        }
+    }
+}
+
+/// The credentials from the recipe, or from the Docker configuration if the recipe has none.
+async fn registry_auth(auth: &Authorization, registry: &str) -> Result<Authorization> {
+    match auth {
+        Authorization::None => docker_config::credentials_for(registry)
+            .await
+            .with_context(|| format!("reading Docker credentials for {registry}")),
+        auth => Ok(auth.clone()),
+    }
+}
+
+/// The base image repository if it lives on the target registry, so that its layers
+/// can be mounted instead of copied.
+fn mount_source(recipe: &Recipe) -> Option<String> {
+    match &recipe.base.image {
+        BaseImage::Registry(reference)
+            if reference.resolve_registry() == recipe.target.registry
+                && reference.repository() != recipe.target.repo =>
+        {
+            Some(reference.repository().to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Open the output for a dry run without creating it, `None` if it doesn't exist yet.
+async fn open_existing_sink(recipe: &Recipe, output: &Output) -> Result<Option<ImageSink>> {
+    match output {
+        // Only `has_blob` is called, which needs no push access
+        Output::Registry => RegistryClient::new(
+            &recipe.target.registry,
+            &recipe.target.repo,
+            &registry_auth(&recipe.target.auth, &recipe.target.registry).await?,
+            ClientScope::Pull,
+            &recipe.target.transport,
+        )
+        .await
+        .context("creating target registry client")
+        .map(|client| Some(ImageSink::Registry(client))),
+        Output::OciLayout(path) => {
+            if !tokio::fs::try_exists(path.join("oci-layout"))
+                .await
+                .into_diagnostic()?
+            {
+                return Ok(None);
+            }
+            ImageLayout::open(path)
+                .await
+                .context("opening target image layout")
+                .map(|layout| Some(ImageSink::OciLayout(layout)))
+        }
+        Output::DockerArchive(_) if recipe.target.is_multi_platform() => {
+            Err(sink::single_platform_only())
+        }
+        // Archives are written from scratch
+        Output::DockerArchive(_) => Ok(None),
+    }
 }
 
-/// Pull the base image manifest + config, build the app layer, and create the target
-/// client -- all three run concurrently.
-async fn pull_base_and_build_app_layer(
+/// Create the provider the base image is read from.
+async fn create_base_provider(recipe: &Recipe) -> Result<BlobProvider> {
+    match &recipe.base.image {
+        BaseImage::Registry(reference) => RegistryClient::new(
+            &reference.resolve_registry(),
+            &reference.repository(),
+            &registry_auth(&recipe.base.auth, reference.resolve_registry()).await?,
+            ClientScope::Pull,
+            &recipe.base.transport,
+        )
+        .await
+        .context("creating base image registry client")
+        .map(BlobProvider::Registry),
+        BaseImage::OciLayout { path, .. } => ImageLayout::open(path)
+            .await
+            .context("opening base image layout")
+            .map(BlobProvider::OciLayout),
+        BaseImage::DockerArchive(path) => DockerArchiveReader::open(path)
+            .await
+            .context("opening base image docker archive")
+            .map(BlobProvider::DockerArchive),
+    }
+}
+
+/// The digest a registry base image is pinned to, `None` for other base images.
+async fn pin_base(
     recipe: &Recipe,
-    base_client: &RegistryClient,
-) -> Result<(ImageManifest, ImageConfiguration, AppLayer, RegistryClient)> {
-    let base_tag = recipe
-        .base
-        .image
-        .digest()
-        .or(recipe.base.image.tag())
-        .unwrap_or("latest");
-
-    let base = base_client
-        .get_tag_for_target(base_tag, Arch::Amd64, Os::Linux)
-        .map_err(|e| e.context("getting base image"));
+    base_provider: &BlobProvider,
+    lock: &mut Lockfile,
+) -> Result<Option<Digest>> {
+    let BlobProvider::Registry(client) = base_provider else {
+        return Ok(None);
+    };
+    let digest = match lock.pinned(&recipe.base.image)? {
+        Some(digest) => digest,
+        None => {
+            client
+                .resolve_digest(recipe.base.image.reference().unwrap_or("latest"))
+                .await?
+        }
+    };
+    info!("using base image {}@{digest}", recipe.base.image);
+    lock.pin(&recipe.base.image, digest.clone());
+    Ok(Some(digest))
+}
 
-    let app_layer = AppLayer::build_from_directory(&recipe.modification.app_layer_folder)
-        .map_err(|e| e.context("building app layer"));
+/// Resolve the base image and build the image for every platform, returning the digest
+/// a registry base image was pinned to along with the images.
+async fn build_platform_images(
+    recipe: &Recipe,
+    platforms: &[Platform],
+    lock: &mut Lockfile,
+) -> Result<(Option<Digest>, Vec<PreparationState>)> {
+    let base_provider = create_base_provider(recipe).await?;
+    let base_digest = pin_base(recipe, &base_provider, lock)
+        .await
+        .context("resolving base image")?;
+    let base_reference = match &base_digest {
+        Some(digest) => Some(digest.to_string()),
+        None => recipe.base.image.reference().map(str::to_owned),
+    };
+    let images = futures::future::try_join_all(platforms.iter().map(|platform| {
+        build_platform_image(recipe, &base_provider, base_reference.as_deref(), platform)
+    }))
+    .await?;
+    Ok((base_digest, images))
+}
 
-    let target_client = RegistryClient::new(
-        &recipe.target.registry,
-        &recipe.target.repo,
-        &recipe.target.auth,
-        ClientScope::Push,
-    )
-    .map_err(|e| e.context("creating target registry client"));
+/// Pull the base image for the platform, build its app layer and assemble the image.
+async fn build_platform_image(
+    recipe: &Recipe,
+    base_provider: &BlobProvider,
+    base_reference: Option<&str>,
+    platform: &Platform,
+) -> Result<PreparationState> {
+    let (base_manifest, base_config, app_layers) =
+        pull_base_and_build_app_layers(recipe, base_provider, base_reference, platform)
+            .await
+            .with_context(|| format!("building image for {platform}"))?;
+
+    let image = assemble_image(
+        recipe,
+        base_manifest,
+        base_config,
+        base_provider.clone(),
+        app_layers,
+    );
+
+    debug!("{platform}: {:?}", &image.manifest());
 
-    let ((base_manifest, base_config), app_layer, target_client) =
-        flatten_results(tokio::join!(base, app_layer, target_client))?;
+    Ok(image)
+}
+
+/// Pull the base image manifest + config and build the app layers concurrently.
+async fn pull_base_and_build_app_layers(
+    recipe: &Recipe,
+    base_provider: &BlobProvider,
+    base_reference: Option<&str>,
+    platform: &Platform,
+) -> Result<(ImageManifest, ImageConfiguration, Vec<AppLayer>)> {
+    let base = base_provider
+        .get_image(base_reference, platform)
+        .map_err(|e| e.context("getting base image"));
 
-    Ok((base_manifest, base_config, app_layer, target_client))
+    let layers = recipe.modification.layers();
+    let folders = layers
+        .iter()
+        .map(|layer| layer.folder.for_platform(platform))
+        .collect::<Result<Vec<_>>>()?;
+    let app_layers =
+        futures::future::try_join_all(folders.into_iter().zip(&layers).map(|(folder, layer)| {
+            AppLayer::build_from_directory(folder, &layer.options, recipe.modification.compression)
+                .map_err(move |e| e.context(format!("building app layer from {folder}")))
+        }));
+
+    let ((base_manifest, base_config), app_layers) = tokio::try_join!(base, app_layers)?;
+
+    Ok((base_manifest, base_config, app_layers))
 }
 
 /// Assemble the new image from the base image and modifications in the recipe.
@@ -89,12 +378,14 @@ fn assemble_image(
     recipe: &Recipe,
     base_manifest: ImageManifest,
     base_config: ImageConfiguration,
-    base_client: RegistryClient,
-    app_layer: AppLayer,
+    base_provider: BlobProvider,
+    app_layers: Vec<AppLayer>,
 ) -> PreparationState {
-    let mut image = PreparationState::new(base_manifest, base_config, base_client);
+    let mut image = PreparationState::new(base_manifest, base_config, base_provider);
 
-    image.apply_layer(app_layer);
+    for app_layer in app_layers {
+        image.apply_layer(app_layer);
+    }
 
     recipe
         .modification
@@ -107,8 +398,48 @@ fn assemble_image(
     image
 }
 
-fn flatten_results<A, B, C, E>(
-    tuple: (Result<A, E>, Result<B, E>, Result<C, E>),
-) -> Result<(A, B, C), E> {
-    Ok((tuple.0?, tuple.1?, tuple.2?))
+/// Push the per-platform images by digest and tag an image index referencing them.
+async fn push_index(
+    recipe: &Recipe,
+    images: Vec<PreparationState>,
+    platforms: &[Platform],
+    sink: &ImageSink,
+    transfers: &Semaphore,
+) -> Result<(Digest, Vec<ImageSummary>)> {
+    let summaries = futures::future::try_join_all(
+        images
+            .into_iter()
+            .zip(platforms.iter())
+            .map(|(image, platform)| image.push_untagged(sink, platform, transfers)),
+    )
+    .await?;
+
+    let manifests = summaries
+        .iter()
+        .map(|summary| summary.manifest_descriptor.clone())
+        .collect();
+    let (body, descriptor) = index_to_blob(recipe, manifests)?;
+    let digest = sink
+        .tag_manifest(&descriptor, body, &recipe.target.tags())
+        .await?;
+    Ok((digest, summaries))
+}
+
+/// Serialize an image index referencing the per-platform manifests.
+fn index_to_blob(recipe: &Recipe, manifests: Vec<Descriptor>) -> Result<(String, Descriptor)> {
+    let index: ImageIndex = ImageIndexBuilder::default()
+        .schema_version(2u32)
+        .media_type(MediaType::ImageIndex)
+        .manifests(manifests)
+        .annotations(recipe.modification.annotations.clone())
+        .build()
+        .into_diagnostic()?;
+
+    let body = index.to_string().into_diagnostic()?;
+    let descriptor = Descriptor::new(
+        MediaType::ImageIndex,
+        body.len() as u64,
+        sha256_digest(body.as_bytes()),
+    );
+    Ok((body, descriptor))
 }
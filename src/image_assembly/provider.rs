@@ -0,0 +1,188 @@
+use std::fmt::Display;
+
+use miette::{Context, IntoDiagnostic, Result};
+use oci_spec::image::{
+    Descriptor, Digest, ImageConfiguration, ImageIndex, ImageManifest, MediaType,
+};
+use tracing::info;
+
+use crate::docker_archive::DockerArchiveReader;
+use crate::oci_layout::ImageLayout;
+use crate::recipe::Platform;
+use crate::registry_client::{BlobStream, RegistryClient};
+
+/// The source the base image and its blobs are read from.
+#[derive(Clone)]
+pub(crate) enum BlobProvider {
+    Registry(RegistryClient),
+    OciLayout(ImageLayout),
+    DockerArchive(DockerArchiveReader),
+}
+
+impl Display for BlobProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlobProvider::Registry(client) => write!(f, "{}/{}", client.registry, client.repo),
+            BlobProvider::OciLayout(layout) => write!(f, "oci-layout:{}", layout.root().display()),
+            BlobProvider::DockerArchive(archive) => {
+                write!(f, "docker-archive:{}", archive.path().display())
+            }
+        }
+    }
+}
+
+impl BlobProvider {
+    pub(crate) async fn get_blob(&self, digest: &Digest) -> Result<bytes::Bytes> {
+        match self {
+            BlobProvider::Registry(client) => client.get_binary_blob(digest).await,
+            BlobProvider::OciLayout(layout) => layout.read_blob(digest).await,
+            BlobProvider::DockerArchive(archive) => archive.read_blob(digest).await,
+        }
+    }
+
+    /// Get a blob as a stream of chunks, for copying it without holding it in memory.
+    pub(crate) async fn get_blob_stream(&self, digest: &Digest) -> Result<BlobStream> {
+        match self {
+            BlobProvider::Registry(client) => client.get_blob_stream(digest).await,
+            BlobProvider::OciLayout(layout) => layout.read_blob_stream(digest).await,
+            BlobProvider::DockerArchive(archive) => archive.read_blob_stream(digest).await,
+        }
+    }
+
+    /// Get the manifest and configuration of the image for the platform.
+    #[tracing::instrument(skip_all)]
+    pub(crate) async fn get_image(
+        &self,
+        reference: Option<&str>,
+        platform: &Platform,
+    ) -> Result<(ImageManifest, ImageConfiguration)> {
+        match self {
+            BlobProvider::Registry(client) => {
+                client
+                    .get_tag_for_target(reference.unwrap_or("latest"), platform)
+                    .await
+            }
+            BlobProvider::OciLayout(layout) => {
+                let descriptor = layout.resolve(reference).await?;
+                self.get_image_from_descriptor(descriptor, platform).await
+            }
+            BlobProvider::DockerArchive(archive) => {
+                let (manifest, configuration) = archive.image();
+                check_platform(&configuration, platform)?;
+                Ok((manifest, configuration))
+            }
+        }
+    }
+
+    /// Follow a descriptor of an index or manifest to the image for the platform.
+    async fn get_image_from_descriptor(
+        &self,
+        mut descriptor: Descriptor,
+        platform: &Platform,
+    ) -> Result<(ImageManifest, ImageConfiguration)> {
+        if *descriptor.media_type() == MediaType::ImageIndex {
+            let index: ImageIndex = self.get_json(descriptor.digest()).await?;
+            descriptor = index
+                .manifests()
+                .iter()
+                .find(|m| m.platform().as_ref().is_some_and(|p| platform.matches(p)))
+                .cloned()
+                .ok_or_else(|| miette::miette!("could not find manifest for {platform}"))?;
+        }
+        info!("using base manifest {} from {self}", descriptor.digest());
+        let manifest: ImageManifest = self.get_json(descriptor.digest()).await?;
+        let configuration: ImageConfiguration = self.get_json(manifest.config().digest()).await?;
+        check_platform(&configuration, platform)?;
+        Ok((manifest, configuration))
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, digest: &Digest) -> Result<T> {
+        let blob = self.get_blob(digest).await?;
+        serde_json::from_slice(&blob)
+            .into_diagnostic()
+            .with_context(|| format!("parsing {digest}"))
+    }
+}
+
+fn check_platform(configuration: &ImageConfiguration, platform: &Platform) -> Result<()> {
+    if platform.matches_config(configuration) {
+        Ok(())
+    } else {
+        Err(miette::miette!(
+            "base image is for {}/{}, not {platform}",
+            configuration.os(),
+            configuration.architecture()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_layer::sha256_digest;
+    use crate::recipe::TagName;
+    use oci_spec::image::{ImageIndexBuilder, ImageManifestBuilder};
+    use tempfile::TempDir;
+    use test_log::test;
+
+    async fn write_json(layout: &ImageLayout, value: &impl serde::Serialize) -> (Digest, u64) {
+        let bytes = serde_json::to_vec(value).unwrap();
+        let digest = sha256_digest(&bytes);
+        layout.write_blob(&digest, &bytes).await.unwrap();
+        (digest, bytes.len() as u64)
+    }
+
+    #[test(tokio::test)]
+    async fn test_get_image_from_oci_layout_index() -> Result<()> {
+        let dir = TempDir::new().into_diagnostic()?;
+        let layout = ImageLayout::create(dir.path()).await?;
+
+        let config = serde_json::json!({
+            "architecture": "arm64",
+            "os": "linux",
+            "rootfs": {"type": "layers", "diff_ids": []}
+        });
+        let (config_digest, config_size) = write_json(&layout, &config).await;
+        let manifest = ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(Descriptor::new(
+                MediaType::ImageConfig,
+                config_size,
+                config_digest.clone(),
+            ))
+            .layers(vec![])
+            .build()
+            .unwrap();
+        let (manifest_digest, manifest_size) = write_json(&layout, &manifest).await;
+        let platform = Platform::try_from("linux/arm64").unwrap();
+        let mut manifest_descriptor =
+            Descriptor::new(MediaType::ImageManifest, manifest_size, manifest_digest);
+        manifest_descriptor.set_platform(Some(platform.to_oci()));
+        let index = ImageIndexBuilder::default()
+            .schema_version(2u32)
+            .manifests(vec![manifest_descriptor])
+            .build()
+            .unwrap();
+        let (index_digest, index_size) = write_json(&layout, &index).await;
+        layout
+            .tag(
+                &Descriptor::new(MediaType::ImageIndex, index_size, index_digest),
+                &[TagName::try_from("base").unwrap()],
+            )
+            .await?;
+
+        let provider = BlobProvider::OciLayout(ImageLayout::open(dir.path()).await?);
+
+        let (manifest, configuration) = provider.get_image(Some("base"), &platform).await?;
+        assert_eq!(manifest.config().digest(), &config_digest);
+        assert_eq!(configuration.os(), &oci_spec::image::Os::Linux);
+
+        assert!(
+            provider
+                .get_image(Some("base"), &Platform::default())
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use std::time::Duration;
+
+use oci_spec::image::{Digest, MediaType};
+use serde::Serialize;
+use serde_with::{DurationSecondsWithFrac, serde_as};
+
+use crate::recipe::{Platform, Recipe};
+
+use super::state::{BlobAction, ImageSummary};
+
+/// What a build did, written as JSON with `--report`.
+#[derive(Serialize, Debug)]
+pub struct BuildReport {
+    /// Whether this was a dry run, whose blob actions and tags are only what a build would do.
+    pub dry_run: bool,
+    pub base: BaseReport,
+    /// Digest of the image manifest, or of the index for multi-platform images.
+    pub digest: Digest,
+    pub tags: Vec<String>,
+    pub images: Vec<ImageReport>,
+    pub timings: Timings,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BaseReport {
+    /// The base image as given in the recipe.
+    pub image: String,
+    /// The digest a registry base image was resolved to.
+    pub digest: Option<Digest>,
+}
+
+/// The image built for one platform.
+#[derive(Serialize, Debug)]
+pub struct ImageReport {
+    pub platform: String,
+    pub manifest_digest: Digest,
+    pub config_digest: Digest,
+    pub layers: Vec<LayerReport>,
+    pub blobs: Vec<BlobReport>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LayerReport {
+    pub digest: Digest,
+    /// Digest of the uncompressed layer, from the configuration.
+    pub diff_id: Option<String>,
+    pub size: u64,
+    pub media_type: MediaType,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct BlobReport {
+    pub digest: Digest,
+    pub size: u64,
+    pub action: BlobAction,
+    /// How long checking for and transferring the blob took, `None` in a dry run.
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    pub seconds: Option<Duration>,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct Timings {
+    /// Resolving the base image and building the app layers.
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub prepare_seconds: Duration,
+    /// Transferring the blobs and manifests to the output.
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub push_seconds: Duration,
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub total_seconds: Duration,
+}
+
+impl BuildReport {
+    pub(crate) fn new(
+        recipe: &Recipe,
+        base_digest: Option<Digest>,
+        digest: Digest,
+        images: impl IntoIterator<Item = (Platform, ImageSummary)>,
+        timings: Timings,
+        dry_run: bool,
+    ) -> Self {
+        Self {
+            dry_run,
+            base: BaseReport {
+                image: recipe.base.image.to_string(),
+                digest: base_digest,
+            },
+            digest,
+            tags: recipe
+                .target
+                .tags()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            images: images
+                .into_iter()
+                .map(|(platform, image)| ImageReport::new(&platform, &image))
+                .collect(),
+            timings,
+        }
+    }
+}
+
+impl ImageReport {
+    fn new(platform: &Platform, image: &ImageSummary) -> Self {
+        let diff_ids = image.configuration.rootfs().diff_ids();
+        Self {
+            platform: platform.to_string(),
+            manifest_digest: image.manifest_descriptor.digest().clone(),
+            config_digest: image.manifest.config().digest().clone(),
+            layers: image
+                .manifest
+                .layers()
+                .iter()
+                .enumerate()
+                .map(|(i, layer)| LayerReport {
+                    digest: layer.digest().clone(),
+                    diff_id: diff_ids.get(i).cloned(),
+                    size: layer.size(),
+                    media_type: layer.media_type().clone(),
+                })
+                .collect(),
+            blobs: image
+                .blobs
+                .iter()
+                .map(|(descriptor, action, duration)| BlobReport {
+                    digest: descriptor.digest().clone(),
+                    size: descriptor.size(),
+                    action: *action,
+                    seconds: *duration,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_layer::sha256_digest;
+    use oci_spec::image::Descriptor;
+
+    #[test]
+    fn test_image_report() {
+        let layer = Descriptor::new(MediaType::ImageLayerGzip, 3, sha256_digest(&[1, 2, 3]));
+        let config = Descriptor::new(MediaType::ImageConfig, 2, sha256_digest(&[4, 5]));
+        let manifest = serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "config": config,
+            "layers": [layer],
+        }))
+        .unwrap();
+        let configuration = serde_json::from_value(serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "rootfs": { "type": "layers", "diff_ids": ["sha256:abc"] }
+        }))
+        .unwrap();
+        let summary = ImageSummary {
+            manifest,
+            manifest_descriptor: Descriptor::new(
+                MediaType::ImageManifest,
+                100,
+                sha256_digest(&[6]),
+            ),
+            configuration,
+            blobs: vec![
+                (
+                    layer.clone(),
+                    BlobAction::Mounted,
+                    Some(Duration::from_millis(250)),
+                ),
+                (config.clone(), BlobAction::Uploaded, None),
+            ],
+        };
+
+        let report =
+            serde_json::to_value(ImageReport::new(&Platform::default(), &summary)).unwrap();
+        assert_eq!(report["platform"], "linux/amd64");
+        assert_eq!(report["config_digest"], config.digest().to_string());
+        assert_eq!(
+            report["layers"],
+            serde_json::json!([{
+                "digest": layer.digest().to_string(),
+                "diff_id": "sha256:abc",
+                "size": 3,
+                "media_type": "application/vnd.oci.image.layer.v1.tar+gzip",
+            }])
+        );
+        assert_eq!(report["blobs"][0]["action"], "mounted");
+        assert_eq!(report["blobs"][0]["seconds"], 0.25);
+        assert_eq!(report["blobs"][1]["action"], "uploaded");
+        assert_eq!(report["blobs"][1]["seconds"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_dry_run_report() {
+        let recipe: Recipe = toml::from_str(
+            r#"
+                [base]
+                image = "gcr.io/distroless/cc-debian12:latest"
+
+                [target]
+                registry = "registry"
+                repo = "repo"
+                tags = ["latest"]
+
+                [modification]
+                app_layer_folder = "folder"
+            "#,
+        )
+        .unwrap();
+        let timings = Timings {
+            prepare_seconds: Duration::from_millis(1500),
+            push_seconds: Duration::ZERO,
+            total_seconds: Duration::from_millis(1500),
+        };
+
+        let report = serde_json::to_value(BuildReport::new(
+            &recipe,
+            None,
+            sha256_digest(&[6]),
+            [],
+            timings,
+            true,
+        ))
+        .unwrap();
+        assert_eq!(report["dry_run"], true);
+        assert_eq!(report["tags"], serde_json::json!(["latest"]));
+        assert_eq!(report["timings"]["prepare_seconds"], 1.5);
+    }
+}
@@ -0,0 +1,189 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use miette::{IntoDiagnostic, Result};
+use oci_spec::image::{Descriptor, Digest, ImageManifest, MediaType};
+
+use crate::docker_archive::DockerArchive;
+use crate::oci_layout::ImageLayout;
+use crate::recipe::TagName;
+use crate::registry_client::{BlobStream, RegistryClient};
+
+/// Where the built image should be written to, as given on the command line.
+#[derive(Debug, Clone, Default)]
+pub enum Output {
+    /// Push to the target registry from the recipe.
+    #[default]
+    Registry,
+    /// Write to an OCI image layout directory.
+    OciLayout(PathBuf),
+    /// Write a tarball for `docker load`.
+    DockerArchive(PathBuf),
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "registry" => Ok(Output::Registry),
+            Some(("oci-layout", path)) if !path.is_empty() => {
+                Ok(Output::OciLayout(PathBuf::from(path)))
+            }
+            Some(("docker-archive", path)) if !path.is_empty() => {
+                Ok(Output::DockerArchive(PathBuf::from(path)))
+            }
+            _ => Err(format!(
+                "invalid output {s:?}, expected `registry`, `oci-layout:<path>` or `docker-archive:<path>`"
+            )),
+        }
+    }
+}
+
+/// The destination blobs and manifests of the built image are written to.
+pub(crate) enum ImageSink {
+    Registry(RegistryClient),
+    OciLayout(ImageLayout),
+    DockerArchive(DockerArchive),
+}
+
+impl Display for ImageSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageSink::Registry(client) => write!(f, "{}/{}", client.registry, client.repo),
+            ImageSink::OciLayout(layout) => write!(f, "oci-layout:{}", layout.root().display()),
+            ImageSink::DockerArchive(archive) => {
+                write!(f, "docker-archive:{}", archive.path().display())
+            }
+        }
+    }
+}
+
+impl ImageSink {
+    pub(crate) async fn has_blob(&self, digest: &Digest) -> Result<bool> {
+        match self {
+            ImageSink::Registry(client) => client.has_blob(digest).await,
+            ImageSink::OciLayout(layout) => layout.has_blob(digest).await,
+            ImageSink::DockerArchive(archive) => Ok(archive.has_blob(digest)),
+        }
+    }
+
+    pub(crate) async fn put_blob(&self, digest: &Digest, contents: Vec<u8>) -> Result<()> {
+        match self {
+            ImageSink::Registry(client) => client.upload_blob(digest, contents).await,
+            ImageSink::OciLayout(layout) => layout.write_blob(digest, &contents).await,
+            ImageSink::DockerArchive(archive) => archive.write_blob(digest, contents).await,
+        }
+    }
+
+    /// Store a blob of the given size from a stream, without holding it in memory.
+    pub(crate) async fn put_blob_stream(
+        &self,
+        digest: &Digest,
+        size: u64,
+        stream: BlobStream,
+    ) -> Result<()> {
+        match self {
+            ImageSink::Registry(client) => client.upload_blob_stream(digest, size, stream).await,
+            ImageSink::OciLayout(layout) => layout.write_blob_stream(digest, stream).await,
+            ImageSink::DockerArchive(archive) => {
+                archive.write_blob_stream(digest, size, stream).await
+            }
+        }
+    }
+
+    /// Try to make a blob known at the target without transferring it, see
+    /// [`RegistryClient::mount_blob`].
+    pub(crate) async fn mount_blob(&self, digest: &Digest) -> Result<bool> {
+        match self {
+            ImageSink::Registry(client) => client.mount_blob(digest).await,
+            ImageSink::OciLayout(_) | ImageSink::DockerArchive(_) => Ok(false),
+        }
+    }
+
+    /// Store a blob spooled to a file, streaming it rather than reading it into memory.
+    pub(crate) async fn put_blob_file(&self, digest: &Digest, source: &Path) -> Result<()> {
+        match self {
+            ImageSink::Registry(client) => client.upload_blob_file(digest, source).await,
+            ImageSink::OciLayout(layout) => layout.write_blob_file(digest, source).await,
+            ImageSink::DockerArchive(archive) => archive.write_blob_file(digest, source).await,
+        }
+    }
+
+    /// Store a manifest or index by its digest only.
+    pub(crate) async fn put_manifest(&self, descriptor: &Descriptor, body: String) -> Result<()> {
+        match self {
+            ImageSink::Registry(client) => {
+                client
+                    .upload_manifest(body, descriptor.media_type(), descriptor.digest())
+                    .await?;
+                Ok(())
+            }
+            ImageSink::OciLayout(layout) => {
+                layout
+                    .write_blob(descriptor.digest(), body.as_bytes())
+                    .await
+            }
+            ImageSink::DockerArchive(_) => Err(single_platform_only()),
+        }
+    }
+
+    /// Store a manifest or index under the given tags, returning its digest.
+    pub(crate) async fn tag_manifest(
+        &self,
+        descriptor: &Descriptor,
+        body: String,
+        tags: &[TagName],
+    ) -> Result<Digest> {
+        match self {
+            ImageSink::Registry(client) => {
+                let uploads = tags
+                    .iter()
+                    .map(|tag| client.upload_manifest(body.clone(), descriptor.media_type(), tag));
+                let mut digests = futures::future::try_join_all(uploads).await?;
+                digests
+                    .pop()
+                    .ok_or_else(|| miette::miette!("no tags to push the image to"))
+            }
+            ImageSink::OciLayout(layout) => {
+                layout
+                    .write_blob(descriptor.digest(), body.as_bytes())
+                    .await?;
+                layout.tag(descriptor, tags).await?;
+                Ok(descriptor.digest().clone())
+            }
+            ImageSink::DockerArchive(archive) => {
+                if *descriptor.media_type() != MediaType::ImageManifest {
+                    return Err(single_platform_only());
+                }
+                let manifest: ImageManifest = serde_json::from_str(&body).into_diagnostic()?;
+                archive.finish(&manifest, tags).await?;
+                Ok(descriptor.digest().clone())
+            }
+        }
+    }
+}
+
+pub(crate) fn single_platform_only() -> miette::Report {
+    miette::miette!("docker archives can only hold single-platform images")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_output_from_str() {
+        assert!(matches!(Output::from_str("registry"), Ok(Output::Registry)));
+        assert!(
+            matches!(Output::from_str("oci-layout:./out"), Ok(Output::OciLayout(p)) if p == Path::new("./out"))
+        );
+        assert!(
+            matches!(Output::from_str("docker-archive:image.tar"), Ok(Output::DockerArchive(p)) if p == Path::new("image.tar"))
+        );
+        assert!(Output::from_str("oci-layout:").is_err());
+        assert!(Output::from_str("tarball:x").is_err());
+    }
+}
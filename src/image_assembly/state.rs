@@ -1,34 +1,182 @@
 use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::{future::Future, pin::Pin};
 
-use futures::TryStreamExt;
-use futures::stream::FuturesUnordered;
-use miette::Result;
+use futures::StreamExt;
+use miette::{Context, IntoDiagnostic, Result};
 use oci_spec::image::HistoryBuilder;
 use oci_spec::image::ImageManifest;
 use oci_spec::image::{Config as ExecConfig, Digest};
 use oci_spec::image::{Descriptor, ImageConfiguration};
-use tracing::info;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
 
 use crate::app_layer::{self, AppLayer};
-use crate::recipe::TagName;
-use crate::registry_client::RegistryClient;
+use crate::recipe::{Platform, TagName};
+use crate::registry_client::{BlobStream, RetryPolicy, TransientError, verify_blob_stream};
 
-/// Ensure the layer with the given digest is known at the target registry,
-/// copying it from the provider if necessary.
+use super::provider::BlobProvider;
+use super::sink::ImageSink;
+
+/// Ensure the layer with the given digest is known at the target,
+/// mounting or copying it from the provider if necessary.
 async fn ensure_base_layer(
-    provider: &RegistryClient,
-    target: &RegistryClient,
-    digest: &Digest,
-) -> Result<()> {
-    if !target.has_blob(digest).await? {
+    provider: &BlobProvider,
+    target: &ImageSink,
+    layer: &Descriptor,
+) -> Result<BlobAction> {
+    let digest = layer.digest();
+    if target.has_blob(digest).await? {
+        info!("base layer {digest} is already known at target");
+        Ok(BlobAction::Skipped)
+    } else if target.mount_blob(digest).await? {
+        info!("base layer {digest} was mounted at target");
+        Ok(BlobAction::Mounted)
+    } else {
         info!("base layer {digest} is not known at target, copying from upstream");
-        let layer = provider.get_binary_blob(digest).await?.to_vec();
-        target.upload_blob(digest, layer).await?;
+        copy_blob(provider, target, layer).await?;
+        Ok(BlobAction::Copied)
+    }
+}
+
+/// Upload a blob unless the target already knows it, e.g. an unchanged app layer.
+async fn ensure_blob(
+    target: &ImageSink,
+    digest: &Digest,
+    upload: impl Future<Output = Result<()>>,
+) -> Result<BlobAction> {
+    if target.has_blob(digest).await? {
+        info!("blob {digest} is already known at target");
+        Ok(BlobAction::Skipped)
     } else {
-        info!("base layer {digest} is already known at target");
+        upload.await?;
+        Ok(BlobAction::Uploaded)
     }
-    Ok(())
+}
+
+/// Stream a blob from the provider to the target, verifying it on the way. The requests
+/// themselves are retried by the registry client, but if the download breaks off partway or
+/// the target fails transiently while receiving the stream, the copy is started over.
+async fn copy_blob(provider: &BlobProvider, target: &ImageSink, layer: &Descriptor) -> Result<()> {
+    let digest = layer.digest();
+    let retry = copy_retry_policy(provider, target);
+    let mut attempt = 1;
+    loop {
+        let broken = Arc::new(AtomicBool::new(false));
+        let stream = provider.get_blob_stream(digest).await?;
+        let stream = watch_for_break(stream, layer.size(), broken.clone());
+        let stream = verify_blob_stream(stream, digest.clone(), layer.size())?;
+        match target.put_blob_stream(digest, layer.size(), stream).await {
+            Ok(()) => return Ok(()),
+            Err(e)
+                if (broken.load(Ordering::Relaxed)
+                    || e.downcast_ref::<TransientError>().is_some())
+                    && attempt < retry.max_attempts =>
+            {
+                let delay = retry.backoff(attempt);
+                warn!("{e:?}, copying base layer {digest} again in {delay:?}");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e).with_context(|| format!("copying base layer {digest}")),
+        }
+    }
+}
+
+/// Pass a blob's chunks through, setting `broken` if the stream fails or ends short of `size`.
+fn watch_for_break(stream: BlobStream, size: u64, broken: Arc<AtomicBool>) -> BlobStream {
+    futures::stream::unfold(Some((stream, 0u64)), move |state| {
+        let broken = broken.clone();
+        async move {
+            let (mut stream, received) = state?;
+            match stream.next().await {
+                Some(Ok(chunk)) => {
+                    let received = received + chunk.len() as u64;
+                    Some((Ok(chunk), Some((stream, received))))
+                }
+                Some(Err(e)) => {
+                    broken.store(true, Ordering::Relaxed);
+                    Some((Err(e), None))
+                }
+                None => {
+                    if received < size {
+                        broken.store(true, Ordering::Relaxed);
+                    }
+                    None
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// How a broken copy is retried: like requests to the registry it involves, but never into
+/// a docker archive, which can't take back a partially written blob.
+fn copy_retry_policy(provider: &BlobProvider, target: &ImageSink) -> RetryPolicy {
+    let policy = match (provider, target) {
+        (_, ImageSink::DockerArchive(_)) => None,
+        (_, ImageSink::Registry(client)) | (BlobProvider::Registry(client), _) => {
+            Some(client.retry_policy())
+        }
+        _ => None,
+    };
+    policy
+        .cloned()
+        .unwrap_or_else(|| RetryPolicy::with_max_attempts(Some(1)))
+}
+
+/// Run a transfer once one of the permits is available.
+async fn limited<T>(transfers: &Semaphore, transfer: impl Future<Output = Result<T>>) -> Result<T> {
+    let _permit = transfers.acquire().await.into_diagnostic()?;
+    transfer.await
+}
+
+/// Run a transfer, measuring how long it takes from when it starts.
+async fn timed<T>(transfer: impl Future<Output = Result<T>>) -> Result<(T, Duration)> {
+    let started = Instant::now();
+    let result = transfer.await?;
+    Ok((result, started.elapsed()))
+}
+
+/// A running transfer of a blob, resulting in what it did and how long that took.
+type BlobTransfer<'a> = Pin<Box<dyn Future<Output = Result<(BlobAction, Duration)>> + Send + 'a>>;
+
+/// What happens to a blob when the image is pushed.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum BlobAction {
+    /// The blob is already known at the target.
+    Skipped,
+    /// A base layer is mounted from another repository on the target registry.
+    Mounted,
+    /// A base layer is copied from the base image source.
+    Copied,
+    /// An app layer or the configuration is uploaded.
+    Uploaded,
+}
+
+impl Display for BlobAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BlobAction::Skipped => "skip",
+            BlobAction::Mounted => "mount",
+            BlobAction::Copied => "copy",
+            BlobAction::Uploaded => "upload",
+        })
+    }
+}
+
+/// The image as it was pushed, or as a dry run would push it.
+pub(crate) struct ImageSummary {
+    pub manifest: ImageManifest,
+    pub manifest_descriptor: Descriptor,
+    pub configuration: ImageConfiguration,
+    /// Every blob of the image with what pushing it did and how long that took, which is
+    /// unknown in a dry run.
+    pub blobs: Vec<(Descriptor, BlobAction, Option<Duration>)>,
 }
 
 pub(crate) struct PreparationState {
@@ -36,14 +184,14 @@ pub(crate) struct PreparationState {
     configuration: ImageConfiguration,
     base_layers: Vec<Descriptor>,
     own_layers: Vec<AppLayer>,
-    base_provider: RegistryClient,
+    base_provider: BlobProvider,
 }
 
 impl PreparationState {
     pub(crate) fn new(
         mut manifest: ImageManifest,
         configuration: ImageConfiguration,
-        base_provider: RegistryClient,
+        base_provider: BlobProvider,
     ) -> Self {
         let base_layers = manifest.layers().clone();
         manifest.set_media_type(Some(oci_spec::image::MediaType::ImageManifest));
@@ -150,48 +298,142 @@ impl PreparationState {
         self.configuration.set_config(Some(exec_config));
     }
 
-    #[tracing::instrument(skip_all)]
-    pub(crate) async fn push_to(
-        mut self,
-        target: &RegistryClient,
-        tags: Vec<TagName>,
-    ) -> Result<Digest> {
-        info!(
-            "pushing image to {}/{}:{tags:?}",
-            target.registry, target.repo
-        );
-        let tasks: FuturesUnordered<Pin<Box<dyn Future<Output = Result<()>> + Send>>> =
-            FuturesUnordered::new();
+    /// Upload all layers and the configuration to the target, leaving only the
+    /// manifest to be pushed. At most as many blobs as `transfers` has permits are
+    /// transferred at the same time. Returns what was done with each blob and how long it
+    /// took, not counting the wait for a permit.
+    async fn push_blobs(
+        &mut self,
+        target: &ImageSink,
+        transfers: &Semaphore,
+    ) -> Result<Vec<(Descriptor, BlobAction, Option<Duration>)>> {
+        let (conf_bytes, conf_desc) = self.finish_config();
+        let mut tasks: Vec<BlobTransfer> = Vec::new();
+        let mut blobs = Vec::new();
 
         for layer in self.base_layers.iter() {
-            tasks.push(Box::pin(ensure_base_layer(
-                &self.base_provider,
-                target,
-                layer.digest(),
+            tasks.push(Box::pin(limited(
+                transfers,
+                timed(ensure_base_layer(&self.base_provider, target, layer)),
             )));
+            blobs.push(layer.clone());
         }
 
-        for layer in self.own_layers {
-            tasks.push(Box::pin(
-                target.upload_blob(layer.descriptor.digest().clone(), layer.contents),
-            ));
+        for layer in std::mem::take(&mut self.own_layers) {
+            blobs.push(layer.descriptor.clone());
+            tasks.push(Box::pin(limited(
+                transfers,
+                timed(async move {
+                    let digest = layer.descriptor.digest();
+                    ensure_blob(target, digest, target.put_blob_file(digest, &layer.blob)).await
+                }),
+            )));
         }
 
+        let conf_digest = conf_desc.digest().clone();
+        tasks.push(Box::pin(limited(
+            transfers,
+            timed(async move {
+                ensure_blob(
+                    target,
+                    &conf_digest,
+                    target.put_blob(&conf_digest, conf_bytes),
+                )
+                .await
+            }),
+        )));
+        blobs.push(conf_desc);
+
+        let actions = futures::future::try_join_all(tasks).await?;
+        Ok(blobs
+            .into_iter()
+            .zip(actions)
+            .map(|(descriptor, (action, duration))| (descriptor, action, Some(duration)))
+            .collect())
+    }
+
+    /// Serialize the configuration and reference it from the manifest.
+    fn finish_config(&mut self) -> (Vec<u8>, Descriptor) {
         let (conf_bytes, conf_desc) = image_configuration_to_blob(&self.configuration);
-        tasks.push(Box::pin(
-            target.upload_blob(conf_desc.digest().clone(), conf_bytes),
-        ));
+        self.manifest.set_config(conf_desc.clone());
+        (conf_bytes, conf_desc)
+    }
+
+    /// Work out what pushing the image to the target would do, only asking it which blobs
+    /// it already has. Without a target, every blob would be transferred.
+    /// Whether a base layer could be mounted isn't known without trying, so it is copied.
+    pub(crate) async fn plan(mut self, target: Option<&ImageSink>) -> Result<ImageSummary> {
+        self.finish_config();
+        let (_, manifest_descriptor) = image_manifest_to_blob(&self.manifest);
 
-        self.manifest.set_config(conf_desc);
-        tasks.try_collect::<Vec<()>>().await?;
+        let blobs = self
+            .base_layers
+            .iter()
+            .map(|layer| (layer.clone(), BlobAction::Copied))
+            .chain(
+                self.own_layers
+                    .iter()
+                    .map(|layer| (layer.descriptor.clone(), BlobAction::Uploaded)),
+            )
+            .chain([(self.manifest.config().clone(), BlobAction::Uploaded)]);
+        let blobs = futures::future::try_join_all(blobs.map(|(descriptor, action)| async move {
+            let known = match target {
+                Some(target) => target.has_blob(descriptor.digest()).await?,
+                None => false,
+            };
+            let action = if known { BlobAction::Skipped } else { action };
+            Ok::<_, miette::Report>((descriptor, action, None))
+        }))
+        .await?;
 
-        let tasks: FuturesUnordered<Pin<Box<dyn Future<Output = Result<Digest>> + Send>>> =
-            FuturesUnordered::new();
-        for tag in tags {
-            tasks.push(Box::pin(target.upload_manifest(self.manifest.clone(), tag)));
+        Ok(self.into_summary(manifest_descriptor, blobs))
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub(crate) async fn push_to(
+        mut self,
+        target: &ImageSink,
+        tags: Vec<TagName>,
+        transfers: &Semaphore,
+    ) -> Result<ImageSummary> {
+        info!("pushing image to {target}:{tags:?}");
+        let blobs = self.push_blobs(target, transfers).await?;
+
+        let (body, mut descriptor) = image_manifest_to_blob(&self.manifest);
+        let digest = target.tag_manifest(&descriptor, body, &tags).await?;
+        descriptor.set_digest(digest);
+        Ok(self.into_summary(descriptor, blobs))
+    }
+
+    /// Push the image by digest only. The manifest descriptor of the summary has the
+    /// platform set for use in an image index.
+    #[tracing::instrument(skip_all)]
+    pub(crate) async fn push_untagged(
+        mut self,
+        target: &ImageSink,
+        platform: &Platform,
+        transfers: &Semaphore,
+    ) -> Result<ImageSummary> {
+        info!("pushing {platform} image to {target}");
+        let blobs = self.push_blobs(target, transfers).await?;
+
+        let (body, mut descriptor) = image_manifest_to_blob(&self.manifest);
+        target.put_manifest(&descriptor, body).await?;
+        descriptor.set_platform(Some(platform.to_oci()));
+        Ok(self.into_summary(descriptor, blobs))
+    }
+
+    fn into_summary(
+        self,
+        manifest_descriptor: Descriptor,
+        blobs: Vec<(Descriptor, BlobAction, Option<Duration>)>,
+    ) -> ImageSummary {
+        ImageSummary {
+            manifest: self.manifest,
+            manifest_descriptor,
+            configuration: self.configuration,
+            blobs,
         }
-        let mut digests = tasks.try_collect::<Vec<Digest>>().await?;
-        Ok(digests.pop().unwrap())
     }
 
     /// Read-only access to the assembled manifest (for debug logging).
@@ -216,10 +458,23 @@ fn image_configuration_to_blob(config: &ImageConfiguration) -> (Vec<u8>, Descrip
     (config_bytes, config_descriptor)
 }
 
+fn image_manifest_to_blob(manifest: &ImageManifest) -> (String, Descriptor) {
+    let manifest_string = manifest.to_string().unwrap();
+    let manifest_descriptor = Descriptor::new(
+        oci_spec::image::MediaType::ImageManifest,
+        manifest_string.len() as u64,
+        app_layer::sha256_digest(manifest_string.as_bytes()),
+    );
+
+    (manifest_string, manifest_descriptor)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::app_layer::AppLayer;
+    use crate::registry_client::RegistryClient;
+    use miette::IntoDiagnostic;
     use oci_spec::image::{
         ConfigBuilder, Descriptor, Digest, ImageConfigurationBuilder, ImageManifestBuilder,
         MediaType, RootFsBuilder,
@@ -268,8 +523,10 @@ mod tests {
     }
 
     fn dummy_app_layer() -> AppLayer {
+        let blob = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        std::fs::write(&blob, [1, 2, 3]).unwrap();
         AppLayer {
-            contents: vec![1, 2, 3],
+            blob,
             descriptor: Descriptor::new(
                 MediaType::ImageLayerGzip,
                 3,
@@ -286,8 +543,8 @@ mod tests {
         }
     }
 
-    fn dummy_client() -> RegistryClient {
-        RegistryClient::test_dummy("test-registry", "test-repo")
+    fn dummy_client() -> BlobProvider {
+        BlobProvider::Registry(RegistryClient::test_dummy("test-registry", "test-repo"))
     }
 
     #[test]
@@ -442,4 +699,221 @@ mod tests {
         assert_eq!(descriptor.size(), bytes.len() as u64);
         assert!(descriptor.digest().to_string().starts_with("sha256:"));
     }
+    #[test]
+    fn test_image_manifest_to_blob() {
+        let manifest = dummy_manifest(vec![dummy_layer_descriptor()]);
+        let (bytes, descriptor) = image_manifest_to_blob(&manifest);
+        assert_eq!(descriptor.media_type(), &MediaType::ImageManifest);
+        assert_eq!(descriptor.size(), bytes.len() as u64);
+        assert_eq!(
+            descriptor.digest(),
+            &app_layer::sha256_digest(bytes.as_bytes())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retries_failed_base_layer_copy() -> Result<()> {
+        use crate::recipe::{Authorization, RegistryTransport};
+        use crate::registry_client::ClientScope;
+        use wiremock::{
+            Mock, MockServer, ResponseTemplate,
+            matchers::{method, path},
+        };
+
+        let mock_server = MockServer::start().await;
+        let registry = mock_server.uri().replace("http://", "");
+        let layer = Descriptor::new(
+            MediaType::ImageLayerGzip,
+            4,
+            app_layer::sha256_digest(&[1, 2, 3, 4]),
+        );
+
+        Mock::given(method("GET"))
+            .and(path("/v2/"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        // The connection breaks off during the first download
+        Mock::given(method("GET"))
+            .and(path(format!("/v2/base/blobs/{}", layer.digest())))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![1, 2]))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/v2/base/blobs/{}", layer.digest())))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![1, 2, 3, 4]))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let provider = BlobProvider::Registry(
+            RegistryClient::new(
+                &registry,
+                "base",
+                &Authorization::None,
+                ClientScope::Pull,
+                &RegistryTransport {
+                    insecure: true,
+                    ..Default::default()
+                },
+            )
+            .await?,
+        );
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let target =
+            ImageSink::OciLayout(crate::oci_layout::ImageLayout::create(dir.path()).await?);
+
+        assert_eq!(
+            ensure_base_layer(&provider, &target, &layer).await?,
+            BlobAction::Copied
+        );
+        assert!(target.has_blob(layer.digest()).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restarts_copy_after_transient_target_failure() -> Result<()> {
+        use crate::recipe::{Authorization, RegistryTransport};
+        use crate::registry_client::ClientScope;
+        use wiremock::{
+            Mock, MockServer, ResponseTemplate,
+            matchers::{method, path},
+        };
+
+        let mock_server = MockServer::start().await;
+        let registry = mock_server.uri().replace("http://", "");
+        let layer = Descriptor::new(
+            MediaType::ImageLayerGzip,
+            4,
+            app_layer::sha256_digest(&[1, 2, 3, 4]),
+        );
+
+        Mock::given(method("GET"))
+            .and(path("/v2/"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/v2/base/blobs/{}", layer.digest())))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![1, 2, 3, 4]))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v2/target/blobs/uploads/"))
+            .respond_with(
+                ResponseTemplate::new(202).insert_header("Location", "/v2/target/blobs/uploads/1"),
+            )
+            .mount(&mock_server)
+            .await;
+        // The target is unavailable while the first copy is streamed to it
+        Mock::given(method("PUT"))
+            .and(path("/v2/target/blobs/uploads/1"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/v2/target/blobs/uploads/1"))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let transport = RegistryTransport {
+            insecure: true,
+            ..Default::default()
+        };
+        let provider = BlobProvider::Registry(
+            RegistryClient::new(
+                &registry,
+                "base",
+                &Authorization::None,
+                ClientScope::Pull,
+                &transport,
+            )
+            .await?,
+        );
+        let target = ImageSink::Registry(
+            RegistryClient::new(
+                &registry,
+                "target",
+                &Authorization::None,
+                ClientScope::Push { mount_from: None },
+                &transport,
+            )
+            .await?,
+        );
+
+        assert_eq!(
+            ensure_base_layer(&provider, &target, &layer).await?,
+            BlobAction::Copied
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plan_skips_known_blobs() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let layout = crate::oci_layout::ImageLayout::create(dir.path()).await?;
+        let base_layer = dummy_layer_descriptor();
+        layout.write_blob(base_layer.digest(), &[0]).await?;
+        let target = ImageSink::OciLayout(layout);
+
+        let mut state = PreparationState::new(
+            dummy_manifest(vec![base_layer]),
+            dummy_config(),
+            dummy_client(),
+        );
+        state.apply_layer(dummy_app_layer());
+        let plan = state.plan(Some(&target)).await?;
+
+        let actions = plan
+            .blobs
+            .iter()
+            .map(|(_, action, _)| *action)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            [
+                BlobAction::Skipped,
+                BlobAction::Uploaded,
+                BlobAction::Uploaded
+            ]
+        );
+        assert_eq!(plan.blobs[2].0, *plan.manifest.config());
+        // A dry run doesn't transfer anything
+        assert!(plan.blobs.iter().all(|(_, _, duration)| duration.is_none()));
+        assert_eq!(
+            plan.manifest_descriptor.digest(),
+            image_manifest_to_blob(&plan.manifest).1.digest()
+        );
+        // Nothing is written to the target
+        assert!(!target.has_blob(plan.manifest.config().digest()).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limited_transfers() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let transfers = Semaphore::new(2);
+        let running = AtomicUsize::new(0);
+        let most_running = AtomicUsize::new(0);
+        futures::future::try_join_all((0..6).map(|_| {
+            limited(&transfers, async {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most_running.fetch_max(now, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            })
+        }))
+        .await
+        .unwrap();
+        assert_eq!(most_running.load(Ordering::SeqCst), 2);
+    }
 }
@@ -0,0 +1,204 @@
+use std::path::{Path, PathBuf};
+
+use miette::{Context, IntoDiagnostic, Result};
+use oci_spec::image::Digest;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::recipe::BaseImage;
+
+/// Name of the lockfile, which is kept next to the recipe.
+const LOCKFILE_NAME: &str = "klt.lock";
+
+const HEADER: &str = "# Written by klt to pin the base images of the recipe.\n\
+                      # Run klt with --update-lock to resolve them again.\n\n";
+
+/// How the lockfile is used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockMode {
+    /// Use the pinned digests, pinning base images that aren't locked yet.
+    Use,
+    /// Resolve every base image again and record the new digests.
+    Update,
+    /// Use the pinned digests and fail if a base image isn't locked.
+    Locked,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct LockContents {
+    #[serde(default, rename = "base")]
+    bases: Vec<LockedBase>,
+}
+
+/// The digest a base image reference resolved to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct LockedBase {
+    image: String,
+    /// Digest of the index or manifest the reference pointed to.
+    digest: Digest,
+}
+
+/// The base image digests pinned in the `klt.lock` next to a recipe.
+#[derive(Debug)]
+pub struct Lockfile {
+    path: PathBuf,
+    mode: LockMode,
+    locked: LockContents,
+    /// The digests used by this build, which [`Lockfile::save`] writes back together with
+    /// the locked ones it didn't use, e.g. those of another recipe in the same directory.
+    resolved: LockContents,
+}
+
+impl Lockfile {
+    /// Load the lockfile of the recipe, or start an empty one if there is none.
+    pub fn for_recipe(recipe_file: &Path, mode: LockMode) -> Result<Self> {
+        let path = recipe_file.with_file_name(LOCKFILE_NAME);
+        let locked = match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .into_diagnostic()
+                .with_context(|| format!("parsing {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => LockContents::default(),
+            Err(e) => {
+                return Err(e)
+                    .into_diagnostic()
+                    .with_context(|| format!("reading {}", path.display()));
+            }
+        };
+        Ok(Self {
+            path,
+            mode,
+            locked,
+            resolved: LockContents::default(),
+        })
+    }
+
+    /// The digest the base image is pinned to, or `None` if it has to be resolved.
+    pub fn pinned(&self, image: &BaseImage) -> Result<Option<Digest>> {
+        if self.mode == LockMode::Update {
+            return Ok(None);
+        }
+        let image = image.to_string();
+        match self.locked.bases.iter().find(|base| base.image == image) {
+            Some(base) => Ok(Some(base.digest.clone())),
+            None if self.mode == LockMode::Locked => Err(miette::miette!(
+                code = "klt::lockfile_outdated",
+                help = "run klt without --locked to update the lockfile",
+                "{} does not pin the base image {image}",
+                self.path.display()
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Record the digest the base image resolved to.
+    pub fn pin(&mut self, image: &BaseImage, digest: Digest) {
+        let image = image.to_string();
+        self.resolved.bases.retain(|base| base.image != image);
+        self.resolved.bases.push(LockedBase { image, digest });
+    }
+
+    /// Write the pinned digests if they changed. In [`LockMode::Locked`], nothing is written.
+    pub fn save(&self) -> Result<()> {
+        if self.mode == LockMode::Locked {
+            return Ok(());
+        }
+        let mut bases = self.locked.bases.clone();
+        for resolved in &self.resolved.bases {
+            match bases.iter_mut().find(|base| base.image == resolved.image) {
+                Some(base) => base.digest = resolved.digest.clone(),
+                None => bases.push(resolved.clone()),
+            }
+        }
+        let contents = LockContents { bases };
+        if contents == self.locked {
+            return Ok(());
+        }
+        let contents = toml::to_string(&contents).into_diagnostic()?;
+        std::fs::write(&self.path, format!("{HEADER}{contents}"))
+            .into_diagnostic()
+            .with_context(|| format!("writing {}", self.path.display()))?;
+        info!("pinned base images in {}", self.path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const DIGEST: &str = "sha256:9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a";
+
+    #[test]
+    fn test_lockfile_roundtrip() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let recipe_file = dir.path().join("recipe.toml");
+        let image = BaseImage::from_str("gcr.io/distroless/cc-debian12:latest").unwrap();
+        let digest = Digest::from_str(DIGEST).into_diagnostic()?;
+
+        let mut lock = Lockfile::for_recipe(&recipe_file, LockMode::Use)?;
+        assert_eq!(lock.pinned(&image)?, None);
+        lock.pin(&image, digest.clone());
+        lock.save()?;
+        assert!(dir.path().join("klt.lock").exists());
+
+        let lock = Lockfile::for_recipe(&recipe_file, LockMode::Use)?;
+        assert_eq!(lock.pinned(&image)?, Some(digest.clone()));
+        let lock = Lockfile::for_recipe(&recipe_file, LockMode::Update)?;
+        assert_eq!(lock.pinned(&image)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_locked_fails_for_changed_recipe() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let recipe_file = dir.path().join("recipe.toml");
+        let image = BaseImage::from_str("gcr.io/distroless/cc-debian12:latest").unwrap();
+        let other = BaseImage::from_str("gcr.io/distroless/static-debian12:latest").unwrap();
+
+        let mut lock = Lockfile::for_recipe(&recipe_file, LockMode::Use)?;
+        lock.pin(&image, Digest::from_str(DIGEST).into_diagnostic()?);
+        lock.save()?;
+
+        let lock = Lockfile::for_recipe(&recipe_file, LockMode::Locked)?;
+        assert!(lock.pinned(&image)?.is_some());
+        let err = lock.pinned(&other).unwrap_err();
+        assert!(err.to_string().contains("does not pin"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_two_recipes_share_the_lockfile() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let app_recipe = dir.path().join("app.toml");
+        let tools_recipe = dir.path().join("tools.toml");
+        let app_image = BaseImage::from_str("gcr.io/distroless/cc-debian12:latest").unwrap();
+        let tools_image = BaseImage::from_str("docker.io/library/alpine:3").unwrap();
+        let digest = Digest::from_str(DIGEST).into_diagnostic()?;
+
+        let mut lock = Lockfile::for_recipe(&app_recipe, LockMode::Use)?;
+        lock.pin(&app_image, digest.clone());
+        lock.save()?;
+        let mut lock = Lockfile::for_recipe(&tools_recipe, LockMode::Use)?;
+        lock.pin(&tools_image, digest.clone());
+        lock.save()?;
+
+        // The second build keeps the pin of the first one
+        let lock = Lockfile::for_recipe(&app_recipe, LockMode::Locked)?;
+        assert_eq!(lock.pinned(&app_image)?, Some(digest.clone()));
+        assert_eq!(lock.pinned(&tools_image)?, Some(digest.clone()));
+
+        // Updating one recipe's pin leaves the other one alone
+        let other = Digest::from_str(
+            "sha256:1010101010101010101010101010101010101010101010101010101010101010",
+        )
+        .into_diagnostic()?;
+        let mut lock = Lockfile::for_recipe(&app_recipe, LockMode::Update)?;
+        lock.pin(&app_image, other.clone());
+        lock.save()?;
+        let lock = Lockfile::for_recipe(&tools_recipe, LockMode::Use)?;
+        assert_eq!(lock.pinned(&app_image)?, Some(other));
+        assert_eq!(lock.pinned(&tools_image)?, Some(digest));
+        Ok(())
+    }
+}
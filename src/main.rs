@@ -5,11 +5,14 @@ use miette::{Context, IntoDiagnostic, Result};
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
 mod app_layer;
+mod docker_archive;
+mod docker_config;
 mod image_assembly;
+mod lockfile;
+mod oci_layout;
 mod recipe;
 mod registry_client;
 
-
 #[derive(Parser)]
 struct Args {
     /// Path to the recipe TOML file
@@ -18,6 +21,50 @@ struct Args {
     /// Output the digest of the resulting image to the specified file
     #[clap(short, long)]
     digest_file: Option<PathBuf>,
+
+    /// Where to write the image: `registry` pushes to the recipe target,
+    /// `oci-layout:<path>` writes an OCI image layout directory,
+    /// `docker-archive:<path>` writes a tarball for `docker load`
+    #[clap(short, long, default_value = "registry")]
+    output: image_assembly::Output,
+
+    /// Compression of the app layers, `gzip[:<level>]` (also `gzip:fast` or `gzip:best`),
+    /// `zstd[:<level>]` or `none`, overriding the recipe
+    #[clap(long)]
+    compression: Option<recipe::LayerCompression>,
+
+    /// Registry to connect to without TLS verification, falling back to plain HTTP
+    /// if it doesn't speak TLS. Can be given multiple times
+    #[clap(long, value_name = "HOST[:PORT]")]
+    insecure_registry: Vec<String>,
+
+    /// How often a registry request failing with a transient error is attempted,
+    /// overriding the recipe
+    #[clap(long)]
+    max_attempts: Option<u32>,
+
+    /// How many blobs are copied or uploaded to the target at the same time,
+    /// overriding the recipe
+    #[clap(long)]
+    max_parallel_transfers: Option<usize>,
+
+    /// Resolve the base image again instead of using the digest pinned in `klt.lock`
+    #[clap(long, conflicts_with = "locked")]
+    update_lock: bool,
+
+    /// Fail if `klt.lock` doesn't pin the base image of the recipe
+    #[clap(long)]
+    locked: bool,
+
+    /// Build the image and print its manifest, config and the blobs that would be
+    /// transferred, without writing to the output or the lockfile
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Write a JSON report of the build to the specified file: the resolved base image,
+    /// the layers, what was done with each blob and timings
+    #[clap(long)]
+    report: Option<PathBuf>,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -29,20 +76,54 @@ async fn main() -> Result<()> {
 }
 
 async fn run(args: Args) -> Result<()> {
-    let recipe = crate::recipe::load_recipe(args.recipe_file)?;
-    let digest = image_assembly::build_image(&recipe).await?;
+    let mut recipe = crate::recipe::load_recipe(&args.recipe_file)?;
+    if let Some(compression) = args.compression {
+        recipe.modification.compression = compression;
+    }
+    if let Some(max_attempts) = args.max_attempts {
+        recipe.base.transport.max_attempts = Some(max_attempts);
+        recipe.target.transport.max_attempts = Some(max_attempts);
+    }
+    if let Some(max_parallel_transfers) = args.max_parallel_transfers {
+        recipe.target.max_parallel_transfers = Some(max_parallel_transfers);
+    }
+    for registry in &args.insecure_registry {
+        recipe.mark_insecure(registry);
+    }
+    let lock_mode = if args.locked {
+        lockfile::LockMode::Locked
+    } else if args.update_lock {
+        lockfile::LockMode::Update
+    } else {
+        lockfile::LockMode::Use
+    };
+    let mut lock = lockfile::Lockfile::for_recipe(&args.recipe_file, lock_mode)?;
+    let report = if args.dry_run {
+        image_assembly::dry_run(&recipe, &args.output, &mut lock).await?
+    } else {
+        let report = image_assembly::build_image(&recipe, &args.output, &mut lock).await?;
+        lock.save()?;
+        report
+    };
+    let digest = &report.digest;
     if let Some(digest_file) = args.digest_file {
         std::fs::write(&digest_file, digest.to_string())
             .into_diagnostic()
             .with_context(|| format!("writing digest {} to {}", digest, digest_file.display()))?;
     }
+    if let Some(report_file) = args.report {
+        let contents = serde_json::to_vec_pretty(&report).into_diagnostic()?;
+        std::fs::write(&report_file, contents)
+            .into_diagnostic()
+            .with_context(|| format!("writing report to {}", report_file.display()))?;
+    }
     Ok(())
 }
 
 fn setup_logging_tracing() -> Result<()> {
     better_panic::install();
     tracing_subscriber::registry()
-        .with(fmt::layer().without_time())
+        .with(fmt::layer().without_time().with_writer(std::io::stderr))
         .with(
             EnvFilter::try_from_default_env()
                 .or_else(|_| EnvFilter::try_new("info"))
@@ -0,0 +1,287 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::StreamExt;
+use miette::{Context, IntoDiagnostic, Result};
+use oci_spec::image::{
+    ANNOTATION_REF_NAME, Descriptor, Digest, ImageIndex, MediaType, OciLayoutBuilder,
+};
+use tracing::{debug, info};
+
+use crate::recipe::TagName;
+use crate::registry_client::BlobStream;
+
+/// An [OCI image layout](https://github.com/opencontainers/image-spec/blob/main/image-layout.md)
+/// directory on the local filesystem.
+#[derive(Clone)]
+pub struct ImageLayout {
+    root: PathBuf,
+}
+
+impl ImageLayout {
+    /// Open the layout at the given path, creating the directory structure if needed.
+    #[tracing::instrument(skip_all)]
+    pub async fn create(root: impl AsRef<Path>) -> Result<Self> {
+        let root = root.as_ref().to_owned();
+        tokio::fs::create_dir_all(root.join("blobs"))
+            .await
+            .into_diagnostic()
+            .with_context(|| format!("creating image layout at {}", root.display()))?;
+
+        let layout_file = root.join("oci-layout");
+        if !tokio::fs::try_exists(&layout_file)
+            .await
+            .into_diagnostic()?
+        {
+            info!("initializing image layout at {}", root.display());
+            let layout = OciLayoutBuilder::default()
+                .image_layout_version("1.0.0")
+                .build()
+                .into_diagnostic()?;
+            tokio::fs::write(&layout_file, serde_json::to_vec(&layout).into_diagnostic()?)
+                .await
+                .into_diagnostic()
+                .with_context(|| format!("writing {}", layout_file.display()))?;
+        }
+
+        Ok(Self { root })
+    }
+
+    /// Open an existing layout for reading.
+    pub async fn open(root: impl AsRef<Path>) -> Result<Self> {
+        let root = root.as_ref().to_owned();
+        if !tokio::fs::try_exists(root.join("oci-layout"))
+            .await
+            .into_diagnostic()?
+        {
+            miette::bail!("{} is not an OCI image layout", root.display());
+        }
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn blob_path(&self, digest: &Digest) -> PathBuf {
+        self.root
+            .join("blobs")
+            .join(digest.algorithm().to_string())
+            .join(digest.digest())
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.root.join("index.json")
+    }
+
+    pub async fn has_blob(&self, digest: &Digest) -> Result<bool> {
+        tokio::fs::try_exists(self.blob_path(digest))
+            .await
+            .into_diagnostic()
+    }
+
+    /// Write a blob, going through a temporary file so that readers never see partial blobs.
+    #[tracing::instrument(skip_all)]
+    pub async fn write_blob(&self, digest: &Digest, contents: &[u8]) -> Result<()> {
+        self.store_blob(digest, async |partial_path| {
+            tokio::fs::write(partial_path, contents).await
+        })
+        .await
+    }
+
+    /// Copy a blob from a file, e.g. an app layer spooled to disk.
+    #[tracing::instrument(skip_all)]
+    pub async fn write_blob_file(&self, digest: &Digest, source: &Path) -> Result<()> {
+        self.store_blob(digest, async |partial_path| {
+            tokio::fs::copy(source, partial_path).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Write a blob from a stream of chunks.
+    #[tracing::instrument(skip_all)]
+    pub async fn write_blob_stream(&self, digest: &Digest, stream: BlobStream) -> Result<()> {
+        self.store_blob(digest, async move |partial_path| {
+            let mut reader = tokio_util::io::StreamReader::new(stream);
+            let mut file = tokio::fs::File::create(partial_path).await?;
+            tokio::io::copy(&mut reader, &mut file).await?;
+            file.sync_all().await
+        })
+        .await
+    }
+
+    async fn store_blob(
+        &self,
+        digest: &Digest,
+        write: impl AsyncFnOnce(&Path) -> std::io::Result<()>,
+    ) -> Result<()> {
+        let path = self.blob_path(digest);
+        debug!("writing blob {digest} to {}", path.display());
+        tokio::fs::create_dir_all(path.parent().unwrap())
+            .await
+            .into_diagnostic()?;
+        // The same blob may be written concurrently, e.g. a base layer shared between platforms
+        static PARTIAL_COUNTER: AtomicU64 = AtomicU64::new(0);
+        let partial_path = path.with_extension(format!(
+            "{}.partial",
+            PARTIAL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        if let Err(e) = write(&partial_path).await {
+            let _ = tokio::fs::remove_file(&partial_path).await;
+            return Err(e)
+                .into_diagnostic()
+                .with_context(|| format!("writing blob {digest}"));
+        }
+        tokio::fs::rename(&partial_path, &path)
+            .await
+            .into_diagnostic()
+            .with_context(|| format!("writing blob {digest}"))
+    }
+
+    /// Read a blob as a stream of chunks.
+    pub async fn read_blob_stream(&self, digest: &Digest) -> Result<BlobStream> {
+        let file = tokio::fs::File::open(self.blob_path(digest))
+            .await
+            .into_diagnostic()
+            .with_context(|| format!("opening blob {digest} in {}", self.root.display()))?;
+        Ok(tokio_util::io::ReaderStream::new(file).boxed())
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn read_blob(&self, digest: &Digest) -> Result<bytes::Bytes> {
+        debug!("reading blob {digest} from {}", self.root.display());
+        tokio::fs::read(self.blob_path(digest))
+            .await
+            .map(bytes::Bytes::from)
+            .into_diagnostic()
+            .with_context(|| format!("reading blob {digest} from {}", self.root.display()))
+    }
+
+    /// Find the manifest or index stored under the tag in `index.json`.
+    /// Without a tag, the layout must contain exactly one image.
+    pub async fn resolve(&self, tag: Option<&str>) -> Result<Descriptor> {
+        let index = self.read_index().await?;
+        let found = match tag {
+            Some(tag) => index.manifests().iter().find(|d| {
+                d.annotations()
+                    .as_ref()
+                    .and_then(|a| a.get(ANNOTATION_REF_NAME))
+                    .is_some_and(|name| name == tag)
+            }),
+            None if index.manifests().len() == 1 => index.manifests().first(),
+            None => miette::bail!(
+                "{} contains {} images, specify a tag",
+                self.root.display(),
+                index.manifests().len()
+            ),
+        };
+        found.cloned().ok_or_else(|| {
+            miette::miette!(
+                "could not find {} in {}",
+                tag.unwrap_or_default(),
+                self.root.display()
+            )
+        })
+    }
+
+    async fn read_index(&self) -> Result<ImageIndex> {
+        let path = self.index_path();
+        if !tokio::fs::try_exists(&path).await.into_diagnostic()? {
+            let mut index = ImageIndex::default();
+            index.set_media_type(Some(MediaType::ImageIndex));
+            return Ok(index);
+        }
+        let contents = tokio::fs::read(&path)
+            .await
+            .into_diagnostic()
+            .with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_slice(&contents)
+            .into_diagnostic()
+            .with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Reference the descriptor from `index.json` under each of the tags,
+    /// replacing entries previously stored under the same tags.
+    #[tracing::instrument(skip_all)]
+    pub async fn tag(&self, descriptor: &Descriptor, tags: &[TagName]) -> Result<()> {
+        let mut index = self.read_index().await?;
+
+        let ref_name = |d: &Descriptor| {
+            d.annotations()
+                .as_ref()
+                .and_then(|a| a.get(ANNOTATION_REF_NAME).cloned())
+        };
+        let mut manifests = index.manifests().clone();
+        manifests.retain(|d| {
+            ref_name(d).is_none_or(|name| !tags.iter().any(|tag| tag.as_str() == name))
+        });
+
+        if tags.is_empty() {
+            manifests.push(descriptor.clone());
+        }
+        for tag in tags {
+            info!(
+                "tagging {} as {tag} in {}",
+                descriptor.digest(),
+                self.root.display()
+            );
+            let mut annotations = descriptor.annotations().clone().unwrap_or_default();
+            annotations.insert(ANNOTATION_REF_NAME.to_string(), tag.to_string());
+            let mut tagged = descriptor.clone();
+            tagged.set_annotations(Some(annotations));
+            manifests.push(tagged);
+        }
+        index.set_manifests(manifests);
+
+        tokio::fs::write(self.index_path(), index.to_string().into_diagnostic()?)
+            .await
+            .into_diagnostic()
+            .context("writing index.json")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_layer::sha256_digest;
+    use tempfile::TempDir;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn test_write_blob_and_tag() -> Result<()> {
+        let dir = TempDir::new().into_diagnostic()?;
+        let layout = ImageLayout::create(dir.path()).await?;
+        assert!(dir.path().join("oci-layout").exists());
+
+        let contents = b"{}";
+        let digest = sha256_digest(contents);
+        assert!(!layout.has_blob(&digest).await?);
+        layout.write_blob(&digest, contents).await?;
+        assert!(layout.has_blob(&digest).await?);
+        assert_eq!(
+            std::fs::read(dir.path().join("blobs/sha256").join(digest.digest())).unwrap(),
+            contents
+        );
+
+        let descriptor = Descriptor::new(MediaType::ImageManifest, 2, digest.clone());
+        let tags = [
+            TagName::try_from("latest").unwrap(),
+            TagName::try_from("v1").unwrap(),
+        ];
+        layout.tag(&descriptor, &tags).await?;
+        // Tagging again replaces the previous entries instead of adding new ones
+        layout.tag(&descriptor, &tags[..1]).await?;
+
+        let index = ImageIndex::from_file(dir.path().join("index.json")).into_diagnostic()?;
+        assert_eq!(index.manifests().len(), 2);
+        assert!(index.manifests().iter().all(|d| d.digest() == &digest));
+
+        let layout = ImageLayout::open(dir.path()).await?;
+        assert_eq!(layout.read_blob(&digest).await?.as_ref(), contents);
+        assert_eq!(layout.resolve(Some("v1")).await?.digest(), &digest);
+        assert!(layout.resolve(Some("v2")).await.is_err());
+        // Ambiguous without a tag
+        assert!(layout.resolve(None).await.is_err());
+        Ok(())
+    }
+}
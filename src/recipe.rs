@@ -1,14 +1,15 @@
 use std::collections::HashMap;
 use std::fmt::Display;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use miette::{Context, IntoDiagnostic, Result};
 use oci_spec::distribution::Reference;
-use oci_spec::image::Config as ExecConfig;
+use oci_spec::image::{Arch, Config as ExecConfig, Os};
 use secrecy::SecretString;
 use serde::Deserialize;
 use serde::{Deserializer, de::Error};
-use serde_with::{DeserializeAs, MapPreventDuplicates, VecSkipError, serde_as};
+use serde_with::{DeserializeAs, DeserializeFromStr, MapPreventDuplicates, VecSkipError, serde_as};
 
 #[serde_as]
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -19,6 +20,10 @@ pub enum Authorization {
         #[serde_as(as = "ShellExpanded")] SecretString,
     ),
     Token(#[serde_as(as = "ShellExpanded")] SecretString),
+    /// An OAuth2 refresh token for the registry's token endpoint, like the identity tokens
+    /// `docker login` stores.
+    #[serde(skip)]
+    IdentityToken(SecretString),
     #[default]
     None,
 }
@@ -28,8 +33,105 @@ pub enum Authorization {
 pub struct BaseSource {
     #[serde(default)]
     pub auth: Authorization,
+    #[serde(flatten)]
+    pub transport: RegistryTransport,
     #[serde_as(as = "ShellExpanded")]
-    pub image: Reference,
+    pub image: BaseImage,
+}
+
+/// How to connect to a registry.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RegistryTransport {
+    /// Accept any TLS certificate, and fall back to plain HTTP if the registry doesn't
+    /// speak TLS at all.
+    #[serde(default)]
+    pub insecure: bool,
+    /// PEM file with CA certificates to trust in addition to the system's.
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// How often a request failing with a transient error is attempted.
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+}
+
+/// Where the base image is taken from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BaseImage {
+    /// An image in a registry, e.g. `gcr.io/distroless/cc-debian12:latest`.
+    Registry(Reference),
+    /// An OCI image layout directory, e.g. `oci-layout:./base-layout:tag`.
+    OciLayout { path: PathBuf, tag: Option<String> },
+    /// A tarball written by `docker save`, e.g. `docker-archive:./base.tar`.
+    DockerArchive(PathBuf),
+}
+
+impl BaseImage {
+    /// The tag or digest to look up in the image source, if it has more than one image.
+    pub fn reference(&self) -> Option<&str> {
+        match self {
+            BaseImage::Registry(reference) => {
+                Some(reference.digest().or(reference.tag()).unwrap_or("latest"))
+            }
+            BaseImage::OciLayout { tag, .. } => tag.as_deref(),
+            BaseImage::DockerArchive(_) => None,
+        }
+    }
+}
+
+impl FromStr for BaseImage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(layout) = s.strip_prefix("oci-layout:") {
+            // The tag is separated by the last colon, unless that is part of the path
+            let (path, tag) = match layout.rsplit_once(':') {
+                Some((path, tag)) if !tag.contains('/') => (path, Some(tag.to_owned())),
+                _ => (layout, None),
+            };
+            Ok(BaseImage::OciLayout {
+                path: PathBuf::from(path),
+                tag,
+            })
+        } else if let Some(path) = s.strip_prefix("docker-archive:") {
+            Ok(BaseImage::DockerArchive(PathBuf::from(path)))
+        } else {
+            Reference::from_str(s)
+                .map(BaseImage::Registry)
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+impl TryFrom<String> for BaseImage {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for BaseImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BaseImage::Registry(reference) => write!(f, "{reference}"),
+            BaseImage::OciLayout { path, tag: None } => {
+                write!(f, "oci-layout:{}", path.display())
+            }
+            BaseImage::OciLayout {
+                path,
+                tag: Some(tag),
+            } => write!(f, "oci-layout:{}:{tag}", path.display()),
+            BaseImage::DockerArchive(path) => write!(f, "docker-archive:{}", path.display()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BaseImage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(Error::custom)
+    }
 }
 
 #[serde_as]
@@ -37,6 +139,8 @@ pub struct BaseSource {
 pub struct Target {
     #[serde(default)]
     pub auth: Authorization,
+    #[serde(flatten)]
+    pub transport: RegistryTransport,
     #[serde_as(as = "ShellExpanded")]
     pub registry: String,
     #[serde_as(as = "ShellExpanded")]
@@ -44,11 +148,33 @@ pub struct Target {
     #[serde_as(as = "VecSkipError<ShellExpanded>")]
     #[serde(default)]
     tags: Vec<TagName>,
+    #[serde(default)]
+    platforms: Vec<Platform>,
+    /// Upload blobs larger than this many bytes in chunks instead of a single request.
+    #[serde(default)]
+    pub upload_chunk_size: Option<u64>,
+    /// How many blobs are copied or uploaded to the target at the same time.
+    #[serde(default)]
+    pub max_parallel_transfers: Option<usize>,
 }
 
 impl Target {
     pub fn tags(&self) -> Vec<TagName> {
-        self.tags.iter().cloned().collect()
+        self.tags.to_vec()
+    }
+
+    /// The platforms to build for, `linux/amd64` if none are given.
+    pub fn platforms(&self) -> Vec<Platform> {
+        if self.platforms.is_empty() {
+            vec![Platform::default()]
+        } else {
+            self.platforms.clone()
+        }
+    }
+
+    /// Whether an image index should be pushed instead of a single manifest.
+    pub fn is_multi_platform(&self) -> bool {
+        !self.platforms.is_empty()
     }
 }
 
@@ -58,17 +184,297 @@ impl Target {
 )]
 pub struct TagName(String);
 
+#[nutype::nutype(
+    sanitize(trim, lowercase),
+    derive(
+        Display,
+        Debug,
+        Clone,
+        Deserialize,
+        TryFrom,
+        Deref,
+        PartialEq,
+        Eq,
+        Hash,
+        Default
+    ),
+    validate(regex = "^[a-z0-9]+/[a-z0-9]+(/[a-z0-9]+)?$"),
+    default = "linux/amd64"
+)]
+pub struct Platform(String);
+
+impl Platform {
+    fn part(&self, index: usize) -> Option<&str> {
+        self.split('/').nth(index)
+    }
+
+    pub fn os(&self) -> Os {
+        Os::from(self.part(0).unwrap())
+    }
+
+    pub fn architecture(&self) -> Arch {
+        Arch::from(self.part(1).unwrap())
+    }
+
+    pub fn variant(&self) -> Option<&str> {
+        self.part(2)
+    }
+
+    /// Whether an image with the given platform can be used for this platform.
+    /// A missing variant matches any variant.
+    pub fn matches(&self, other: &oci_spec::image::Platform) -> bool {
+        *other.os() == self.os()
+            && *other.architecture() == self.architecture()
+            && self
+                .variant()
+                .is_none_or(|variant| other.variant().as_deref() == Some(variant))
+    }
+
+    /// Whether an image with the configuration runs on this platform.
+    pub fn matches_config(&self, configuration: &oci_spec::image::ImageConfiguration) -> bool {
+        let mut image_platform = oci_spec::image::Platform::default();
+        image_platform.set_os(configuration.os().clone());
+        image_platform.set_architecture(configuration.architecture().clone());
+        image_platform.set_variant(configuration.variant().clone());
+        self.matches(&image_platform)
+    }
+
+    pub fn to_oci(&self) -> oci_spec::image::Platform {
+        let mut platform = oci_spec::image::Platform::default();
+        platform.set_os(self.os());
+        platform.set_architecture(self.architecture());
+        platform.set_variant(self.variant().map(str::to_owned));
+        platform
+    }
+}
+
+/// The folder for an app layer, either shared by all platforms or given per platform.
+#[serde_as]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum AppLayerFolder {
+    Shared(#[serde_as(as = "ShellExpanded")] String),
+    PerPlatform(#[serde_as(as = "HashMap<_, ShellExpanded>")] HashMap<Platform, String>),
+}
+
+impl AppLayerFolder {
+    pub fn for_platform(&self, platform: &Platform) -> Result<&str> {
+        match self {
+            AppLayerFolder::Shared(folder) => Ok(folder),
+            AppLayerFolder::PerPlatform(folders) => folders
+                .get(platform)
+                .map(String::as_str)
+                .ok_or_else(|| miette::miette!("no app layer folder given for {platform}")),
+        }
+    }
+}
+
+/// A layer built from a folder on the local filesystem.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AppLayerSpec {
+    pub folder: AppLayerFolder,
+    #[serde(flatten)]
+    pub options: LayerOptions,
+}
+
+/// How the contents of a folder are put into a layer.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LayerOptions {
+    /// Where the folder's contents are placed inside the image.
+    #[serde(default = "default_dest")]
+    pub dest: String,
+    /// Whether the parent directories of `dest` are added to the layer. Leave them out to
+    /// keep their ownership and permissions from the base image.
+    #[serde(default = "default_parents")]
+    pub parents: bool,
+    /// Default owner of all entries.
+    #[serde(default)]
+    pub uid: u64,
+    #[serde(default)]
+    pub gid: u64,
+    /// Owners of paths inside the image, applied recursively.
+    #[serde(default)]
+    pub chown: HashMap<String, Owner>,
+    /// Modes of paths inside the image, applied to the exact path only.
+    #[serde(default)]
+    pub chmod: HashMap<String, FileMode>,
+    /// Globs relative to the folder; if given, only matching files are added.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs relative to the folder of files and directories to leave out.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl Default for LayerOptions {
+    fn default() -> Self {
+        Self {
+            dest: default_dest(),
+            parents: default_parents(),
+            uid: 0,
+            gid: 0,
+            chown: HashMap::new(),
+            chmod: HashMap::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+fn default_dest() -> String {
+    "/".to_string()
+}
+
+fn default_parents() -> bool {
+    true
+}
+
+/// A `uid:gid` pair, or just a `uid` to use the same value for both.
+#[derive(DeserializeFromStr, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u64,
+    pub gid: u64,
+}
+
+impl FromStr for Owner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |id: &str| {
+            id.parse::<u64>()
+                .map_err(|e| format!("invalid owner {s:?}: {e}"))
+        };
+        match s.split_once(':') {
+            Some((uid, gid)) => Ok(Owner {
+                uid: parse(uid)?,
+                gid: parse(gid)?,
+            }),
+            None => parse(s).map(|id| Owner { uid: id, gid: id }),
+        }
+    }
+}
+
+/// Permission bits given as an octal string, e.g. `"0755"`.
+#[derive(DeserializeFromStr, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMode(pub u32);
+
+impl FromStr for FileMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match u32::from_str_radix(s, 8) {
+            Ok(mode) if mode <= 0o7777 => Ok(FileMode(mode)),
+            _ => Err(format!(
+                "invalid file mode {s:?}, expected octal like \"0755\""
+            )),
+        }
+    }
+}
+
+/// Compression of app layers, given as `gzip[:<level>]`, `zstd[:<level>]` or `none`.
+/// The gzip level is 0 to 9 or one of `fast` and `best`.
+#[derive(DeserializeFromStr, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerCompression {
+    Gzip { level: u32 },
+    Zstd { level: i32 },
+    None,
+}
+
+impl LayerCompression {
+    const DEFAULT_GZIP_LEVEL: u32 = 1;
+    const DEFAULT_ZSTD_LEVEL: i32 = 3;
+}
+
+impl Default for LayerCompression {
+    fn default() -> Self {
+        LayerCompression::Gzip {
+            level: Self::DEFAULT_GZIP_LEVEL,
+        }
+    }
+}
+
+impl FromStr for LayerCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (codec, level) = match s.split_once(':') {
+            Some((codec, level)) => (codec, Some(level)),
+            None => (s, None),
+        };
+        match (codec, level) {
+            ("gzip", None) => Ok(LayerCompression::default()),
+            ("gzip", Some("fast")) => Ok(LayerCompression::Gzip { level: 1 }),
+            ("gzip", Some("best")) => Ok(LayerCompression::Gzip { level: 9 }),
+            ("gzip", Some(level)) => {
+                let level = level
+                    .parse()
+                    .map_err(|e| format!("invalid gzip level {level:?}: {e}"))?;
+                if level > 9 {
+                    return Err(format!("gzip level must be between 0 and 9, got {level}"));
+                }
+                Ok(LayerCompression::Gzip { level })
+            }
+            ("zstd", None) => Ok(LayerCompression::Zstd {
+                level: Self::DEFAULT_ZSTD_LEVEL,
+            }),
+            ("zstd", Some(level)) => {
+                let level = level
+                    .parse()
+                    .map_err(|e| format!("invalid zstd level {level:?}: {e}"))?;
+                if !(1..=22).contains(&level) {
+                    return Err(format!("zstd level must be between 1 and 22, got {level}"));
+                }
+                Ok(LayerCompression::Zstd { level })
+            }
+            ("none", None) => Ok(LayerCompression::None),
+            _ => Err(format!(
+                "invalid compression {s:?}, expected `gzip[:<level>]`, `zstd[:<level>]` or `none`"
+            )),
+        }
+    }
+}
+
+impl Display for LayerCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayerCompression::Gzip { level } => write!(f, "gzip:{level}"),
+            LayerCompression::Zstd { level } => write!(f, "zstd:{level}"),
+            LayerCompression::None => write!(f, "none"),
+        }
+    }
+}
+
 #[serde_as]
 #[derive(Deserialize, Debug, Clone)]
 pub struct ImageModification {
     pub execution_config: Option<ExecConfig>,
-    #[serde_as(as = "ShellExpanded")]
-    pub app_layer_folder: String,
+    #[serde(default)]
+    app_layer_folder: Option<AppLayerFolder>,
+    #[serde(default)]
+    layers: Vec<AppLayerSpec>,
+    /// How the app layers are compressed.
+    #[serde(default)]
+    pub compression: LayerCompression,
     #[serde_as(as = "MapPreventDuplicates<_, ShellExpanded>")]
     #[serde(default)]
     pub annotations: HashMap<String, String>,
 }
 
+impl ImageModification {
+    /// The app layers in the order they are added to the image.
+    /// `app_layer_folder` is shorthand for a single layer added before all others.
+    pub fn layers(&self) -> Vec<AppLayerSpec> {
+        self.app_layer_folder
+            .iter()
+            .map(|folder| AppLayerSpec {
+                folder: folder.clone(),
+                options: LayerOptions::default(),
+            })
+            .chain(self.layers.iter().cloned())
+            .collect()
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Recipe {
     pub base: BaseSource,
@@ -76,6 +482,20 @@ pub struct Recipe {
     pub modification: ImageModification,
 }
 
+impl Recipe {
+    /// Connect insecurely to the registry wherever the base or target image lives on it.
+    pub fn mark_insecure(&mut self, registry: &str) {
+        if let BaseImage::Registry(reference) = &self.base.image
+            && reference.resolve_registry() == registry
+        {
+            self.base.transport.insecure = true;
+        }
+        if self.target.registry == registry {
+            self.target.transport.insecure = true;
+        }
+    }
+}
+
 #[allow(dead_code)]
 struct ShellExpanded;
 
@@ -90,18 +510,31 @@ where
     {
         let s = String::deserialize(deserializer).map_err(Error::custom)?;
         let expanded = shellexpand::env(&s).map_err(Error::custom)?;
-        Ok(T::try_from(expanded.into_owned()).map_err(|e| Error::custom(e.to_string()))?)
+        T::try_from(expanded.into_owned()).map_err(|e| Error::custom(e.to_string()))
     }
 }
 
 pub fn load_recipe(file: impl AsRef<Path>) -> Result<Recipe> {
-    toml::from_str(
+    let recipe: Recipe = toml::from_str(
         &std::fs::read_to_string(file)
             .into_diagnostic()
             .context("Failed to read recipe")?,
     )
     .into_diagnostic()
-    .context("Failed to parse recipe")
+    .context("Failed to parse recipe")?;
+    if recipe.target.tags.is_empty() {
+        miette::bail!(
+            help = "tags whose environment variable is unset or empty are left out",
+            "the recipe has no target tags to push the image to"
+        );
+    }
+    if recipe.modification.layers().is_empty() {
+        miette::bail!(
+            help = "set `app_layer_folder` or add `[[modification.layers]]`",
+            "the recipe adds no app layer"
+        );
+    }
+    Ok(recipe)
 }
 
 #[cfg(test)]
@@ -156,7 +589,10 @@ mod tests {
             "#;
 
             let recipe: Recipe = toml::from_str(toml_content).unwrap();
-            assert_eq!(recipe.base.image.repository(), "test_value/some/repo");
+            let BaseImage::Registry(image) = recipe.base.image else {
+                panic!("Expected registry base image");
+            };
+            assert_eq!(image.repository(), "test_value/some/repo");
         })
     }
 
@@ -181,9 +617,17 @@ mod tests {
         file.write_all(content.as_bytes()).unwrap();
 
         let recipe = load_recipe(file.path())?;
-        assert_eq!(recipe.base.image.registry(), "registry.io");
+        let BaseImage::Registry(image) = &recipe.base.image else {
+            panic!("Expected registry base image");
+        };
+        assert_eq!(image.registry(), "registry.io");
         assert_eq!(recipe.target.repo, "repo");
-        assert_eq!(recipe.modification.app_layer_folder, "folder");
+        assert_eq!(
+            recipe.modification.layers()[0]
+                .folder
+                .for_platform(&Platform::default())?,
+            "folder"
+        );
         assert_eq!(
             recipe
                 .modification
@@ -198,6 +642,230 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_load_recipe_requires_tags() {
+        let mut file = NamedTempFile::new().unwrap();
+        let content = r#"
+            [base]
+            image = "registry.io/repo:tag"
+
+            [target]
+            registry = "registry"
+            repo = "repo"
+            tags = ["$UNSET_TAG_VAR"]
+
+            [modification]
+            app_layer_folder = "folder"
+        "#;
+        file.write_all(content.as_bytes()).unwrap();
+
+        let err = load_recipe(file.path()).unwrap_err();
+        assert!(err.to_string().contains("no target tags"), "{err}");
+    }
+
+    #[test]
+    fn test_load_recipe_requires_app_layer() {
+        let mut file = NamedTempFile::new().unwrap();
+        let content = r#"
+            [base]
+            image = "registry.io/repo:tag"
+
+            [target]
+            registry = "registry"
+            repo = "repo"
+            tags = ["tag"]
+
+            [modification]
+        "#;
+        file.write_all(content.as_bytes()).unwrap();
+
+        let err = load_recipe(file.path()).unwrap_err();
+        assert!(err.to_string().contains("no app layer"), "{err}");
+    }
+
+    #[test]
+    fn test_multiple_layers() -> miette::Result<()> {
+        let toml_content = r#"
+            app_layer_folder = "bin"
+
+            [[layers]]
+            folder = "static"
+            dest = "/opt/app"
+
+            [[layers]]
+            folder = { "linux/amd64" = "amd64/data" }
+            uid = 1000
+            gid = 1000
+            chown = { "/data/cache" = "0" }
+            chmod = { "/data/cache" = "0700" }
+            include = ["**/*.bin"]
+            exclude = ["*.pdb", "tests/"]
+        "#;
+        let modification: ImageModification = toml::from_str(toml_content).unwrap();
+        let platform = Platform::default();
+        let folders = modification
+            .layers()
+            .iter()
+            .map(|layer| layer.folder.for_platform(&platform).map(str::to_owned))
+            .collect::<miette::Result<Vec<_>>>()?;
+        assert_eq!(folders, vec!["bin", "static", "amd64/data"]);
+        let layers = modification.layers();
+        let dests = layers
+            .iter()
+            .map(|layer| layer.options.dest.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(dests, vec!["/", "/opt/app", "/"]);
+        assert_eq!(layers[1].options.uid, 0);
+        let options = &layers[2].options;
+        assert_eq!((options.uid, options.gid), (1000, 1000));
+        assert_eq!(options.chown["/data/cache"], Owner { uid: 0, gid: 0 });
+        assert_eq!(options.chmod["/data/cache"], FileMode(0o700));
+        assert_eq!(options.include, vec!["**/*.bin"]);
+        assert_eq!(options.exclude, vec!["*.pdb", "tests/"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_compression_from_str() {
+        assert_eq!(
+            "gzip".parse::<LayerCompression>().unwrap(),
+            LayerCompression::Gzip { level: 1 }
+        );
+        assert_eq!(
+            "gzip:best".parse::<LayerCompression>().unwrap(),
+            LayerCompression::Gzip { level: 9 }
+        );
+        assert_eq!(
+            "gzip:6".parse::<LayerCompression>().unwrap(),
+            LayerCompression::Gzip { level: 6 }
+        );
+        assert!("gzip:10".parse::<LayerCompression>().is_err());
+        assert_eq!(
+            "none".parse::<LayerCompression>().unwrap(),
+            LayerCompression::None
+        );
+        assert_eq!(
+            "zstd".parse::<LayerCompression>().unwrap(),
+            LayerCompression::Zstd { level: 3 }
+        );
+        assert_eq!(
+            "zstd:19".parse::<LayerCompression>().unwrap(),
+            LayerCompression::Zstd { level: 19 }
+        );
+        assert!("zstd:23".parse::<LayerCompression>().is_err());
+        assert!("none:1".parse::<LayerCompression>().is_err());
+        assert!("brotli".parse::<LayerCompression>().is_err());
+
+        let modification: ImageModification = toml::from_str(r#"compression = "zstd:7""#).unwrap();
+        assert_eq!(
+            modification.compression,
+            LayerCompression::Zstd { level: 7 }
+        );
+    }
+
+    #[test]
+    fn test_owner_and_mode_from_str() {
+        assert_eq!(
+            "1000:2000".parse::<Owner>().unwrap(),
+            Owner {
+                uid: 1000,
+                gid: 2000
+            }
+        );
+        assert_eq!(
+            "65532".parse::<Owner>().unwrap(),
+            Owner {
+                uid: 65532,
+                gid: 65532
+            }
+        );
+        assert!("user:group".parse::<Owner>().is_err());
+        assert_eq!("0755".parse::<FileMode>().unwrap(), FileMode(0o755));
+        assert!("0789".parse::<FileMode>().is_err());
+        assert!("17777".parse::<FileMode>().is_err());
+    }
+
+    #[test]
+    fn test_base_image_from_str() {
+        let registry: BaseImage = "registry.io/repo:tag".parse().unwrap();
+        assert_eq!(registry.reference(), Some("tag"));
+        assert_eq!(
+            "registry.io/repo".parse::<BaseImage>().unwrap().reference(),
+            Some("latest")
+        );
+        assert_eq!(
+            "oci-layout:./base-layout:tag".parse::<BaseImage>().unwrap(),
+            BaseImage::OciLayout {
+                path: PathBuf::from("./base-layout"),
+                tag: Some("tag".to_string())
+            }
+        );
+        assert_eq!(
+            "oci-layout:C:/layout".parse::<BaseImage>().unwrap(),
+            BaseImage::OciLayout {
+                path: PathBuf::from("C:/layout"),
+                tag: None
+            }
+        );
+        let archive: BaseImage = "docker-archive:./base.tar".parse().unwrap();
+        assert_eq!(
+            archive,
+            BaseImage::DockerArchive(PathBuf::from("./base.tar"))
+        );
+        assert_eq!(archive.reference(), None);
+        assert_eq!(archive.to_string(), "docker-archive:./base.tar");
+    }
+
+    #[test]
+    fn test_platforms() -> miette::Result<()> {
+        let toml_content = r#"
+            [base]
+            image = "registry.io/repo:tag"
+
+            [target]
+            registry = "registry"
+            repo = "repo"
+            platforms = ["linux/amd64", "linux/arm/v7"]
+
+            [modification.app_layer_folder]
+            "linux/amd64" = "out/amd64"
+            "linux/arm/v7" = "out/armv7"
+        "#;
+        let recipe: Recipe = toml::from_str(toml_content).unwrap();
+        assert!(recipe.target.is_multi_platform());
+        let platforms = recipe.target.platforms();
+        assert_eq!(platforms.len(), 2);
+        assert_eq!(platforms[1].os(), Os::Linux);
+        assert_eq!(platforms[1].architecture(), Arch::ARM);
+        assert_eq!(platforms[1].variant(), Some("v7"));
+        let layers = recipe.modification.layers();
+        assert_eq!(layers[0].folder.for_platform(&platforms[1])?, "out/armv7");
+        assert!(
+            layers[0]
+                .folder
+                .for_platform(&Platform::try_from("linux/arm64").unwrap())
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_platform_matches() {
+        let arm64 = Platform::try_from("linux/arm64").unwrap();
+        let armv7 = Platform::try_from("linux/arm/v7").unwrap();
+        let mut oci = oci_spec::image::Platform::default();
+        oci.set_os(Os::Linux);
+        oci.set_architecture(Arch::ARM64);
+        oci.set_variant(Some("v8".to_string()));
+        assert!(arm64.matches(&oci));
+        assert!(!armv7.matches(&oci));
+        oci.set_architecture(Arch::ARM);
+        oci.set_variant(Some("v7".to_string()));
+        assert!(armv7.matches(&oci));
+        assert!(Platform::try_from("linux").is_err());
+        assert_eq!(Platform::default().to_oci().architecture(), &Arch::Amd64);
+    }
+
     #[test]
     fn test_authorization_deserialization() {
         let toml_content = r#"
@@ -233,4 +901,31 @@ mod tests {
 
         assert!(matches!(auths.auth3, Authorization::None));
     }
+
+    #[test]
+    fn test_registry_transport() {
+        let toml_content = r#"
+            [base]
+            image = "localhost:5000/base:tag"
+
+            [target]
+            registry = "registry.internal"
+            repo = "repo"
+            ca_file = "certs/ca.pem"
+
+            [modification]
+            app_layer_folder = "folder"
+        "#;
+
+        let mut recipe: Recipe = toml::from_str(toml_content).unwrap();
+        assert_eq!(recipe.base.transport, RegistryTransport::default());
+        assert_eq!(
+            recipe.target.transport.ca_file,
+            Some(PathBuf::from("certs/ca.pem"))
+        );
+
+        recipe.mark_insecure("localhost:5000");
+        assert!(recipe.base.transport.insecure);
+        assert!(!recipe.target.transport.insecure);
+    }
 }
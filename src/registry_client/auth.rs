@@ -0,0 +1,331 @@
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use miette::{Context, IntoDiagnostic, Result};
+use reqwest::{Client, Url};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+/// Lifetime of a token whose response doesn't state one, as the token spec prescribes.
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Tokens are renewed this long before they expire, so that they don't expire in flight.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+/// Client id sent to token endpoints, which some require for refresh tokens.
+const CLIENT_ID: &str = "klt";
+
+/// How a registry wants to be authenticated.
+#[derive(Default)]
+pub enum AuthMethod {
+    /// The registry allows anonymous access.
+    #[default]
+    Anonymous,
+    /// Tokens are handed out by the `realm` endpoint.
+    Bearer {
+        realm: String,
+        service: Option<String>,
+    },
+    /// Credentials are sent with every request.
+    Basic,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+    /// Lifetime of the token in seconds.
+    expires_in: Option<u64>,
+    refresh_token: Option<String>,
+}
+
+/// A bearer token and what is needed to renew it.
+struct Token {
+    value: String,
+    expires_at: Instant,
+    refresh_token: Option<String>,
+}
+
+impl Token {
+    fn from_response(response: TokenResponse, realm: &str) -> Result<Self> {
+        let value = response
+            .token
+            .or(response.access_token)
+            .ok_or_else(|| miette::miette!("token response from {realm} contains no token"))?;
+        let lifetime = response
+            .expires_in
+            .map_or(DEFAULT_TOKEN_LIFETIME, Duration::from_secs);
+        Ok(Self {
+            value,
+            expires_at: Instant::now() + lifetime,
+            refresh_token: response.refresh_token,
+        })
+    }
+
+    fn expires_soon(&self) -> bool {
+        Instant::now() + EXPIRY_MARGIN >= self.expires_at
+    }
+
+    fn authorization(&self) -> String {
+        format!("Bearer {}", self.value)
+    }
+}
+
+/// Produces the `Authorization` header for requests to a registry, renewing bearer tokens
+/// before they expire or when the registry rejects them.
+#[derive(Default)]
+pub struct Authenticator {
+    client: Client,
+    method: AuthMethod,
+    credentials: Option<(String, String)>,
+    /// Refresh token to get the first token with, instead of the credentials.
+    refresh_token: Option<String>,
+    /// The scopes bearer tokens are requested for.
+    scopes: Vec<String>,
+    token: Mutex<Option<Token>>,
+}
+
+impl Authenticator {
+    pub fn new(
+        client: Client,
+        method: AuthMethod,
+        credentials: Option<(String, String)>,
+        scopes: Vec<String>,
+    ) -> Self {
+        Self {
+            client,
+            method,
+            credentials,
+            refresh_token: None,
+            scopes,
+            token: Mutex::default(),
+        }
+    }
+
+    /// Get tokens by exchanging the refresh token, e.g. an identity token from `docker login`.
+    pub fn with_refresh_token(self, refresh_token: Option<String>) -> Self {
+        Self {
+            refresh_token,
+            ..self
+        }
+    }
+
+    /// Whether a rejected request may succeed with a new token.
+    pub fn can_renew(&self) -> bool {
+        matches!(self.method, AuthMethod::Bearer { .. })
+    }
+
+    /// The `Authorization` header value for the next request, if any.
+    pub async fn authorization(&self) -> Result<Option<String>> {
+        match &self.method {
+            AuthMethod::Bearer { .. } => self.bearer_authorization(None).await.map(Some),
+            AuthMethod::Anonymous | AuthMethod::Basic => {
+                Ok(self.credentials.as_ref().map(|(username, password)| {
+                    let encoded = base64::engine::general_purpose::STANDARD
+                        .encode(format!("{username}:{password}"));
+                    format!("Basic {encoded}")
+                }))
+            }
+        }
+    }
+
+    /// Get a new token after the registry rejected the `rejected` header value.
+    /// Concurrent requests rejected with the same token share one renewal.
+    pub async fn renew(&self, rejected: &str) -> Result<String> {
+        self.bearer_authorization(Some(rejected)).await
+    }
+
+    /// The current bearer token, fetching a new one if it is about to expire or equals `rejected`.
+    async fn bearer_authorization(&self, rejected: Option<&str>) -> Result<String> {
+        let AuthMethod::Bearer { realm, service } = &self.method else {
+            miette::bail!("registry does not use bearer tokens");
+        };
+        let mut token = self.token.lock().await;
+        if let Some(current) = token.as_ref()
+            && !current.expires_soon()
+            && rejected.is_none_or(|rejected| rejected != current.authorization())
+        {
+            return Ok(current.authorization());
+        }
+
+        let refresh_token = token
+            .take()
+            .and_then(|current| current.refresh_token)
+            .or_else(|| self.refresh_token.clone());
+        let renewed = match refresh_token {
+            Some(refresh_token) => {
+                match self
+                    .refresh_token(realm, service.as_deref(), &refresh_token)
+                    .await
+                {
+                    Ok(renewed) => renewed,
+                    Err(e) => {
+                        debug!("{e:?}, requesting a new token instead");
+                        self.fetch_token(realm, service.as_deref()).await?
+                    }
+                }
+            }
+            None => self.fetch_token(realm, service.as_deref()).await?,
+        };
+        let authorization = renewed.authorization();
+        *token = Some(renewed);
+        Ok(authorization)
+    }
+
+    /// Request a bearer token for the scopes from the token endpoint, authenticating with the
+    /// credentials if given.
+    async fn fetch_token(&self, realm: &str, service: Option<&str>) -> Result<Token> {
+        debug!("requesting token from {realm}");
+        let mut params = service
+            .map(|service| ("service", service))
+            .into_iter()
+            .chain(self.scopes.iter().map(|scope| ("scope", scope.as_str())))
+            .collect::<Vec<_>>();
+        if self.credentials.is_some() {
+            // Ask for a refresh token, so that credentials needn't be sent again
+            params.extend([("offline_token", "true"), ("client_id", CLIENT_ID)]);
+        }
+        let token_url = Url::parse_with_params(realm, params).into_diagnostic()?;
+        let mut request = self.client.get(token_url);
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        let response = request
+            .send()
+            .await
+            .into_diagnostic()?
+            .error_for_status()
+            .into_diagnostic()
+            .with_context(|| format!("requesting token from {realm}"))?
+            .json::<TokenResponse>()
+            .await
+            .into_diagnostic()?;
+        Token::from_response(response, realm)
+    }
+
+    /// Exchange a refresh token for a new token with the OAuth2 flow of the token endpoint.
+    async fn refresh_token(
+        &self,
+        realm: &str,
+        service: Option<&str>,
+        refresh_token: &str,
+    ) -> Result<Token> {
+        debug!("refreshing token at {realm}");
+        let scope = self.scopes.join(" ");
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_id", CLIENT_ID),
+            ("scope", &scope),
+        ];
+        form.extend(service.map(|service| ("service", service)));
+        let response = self
+            .client
+            .post(realm)
+            .form(&form)
+            .send()
+            .await
+            .into_diagnostic()?
+            .error_for_status()
+            .into_diagnostic()
+            .with_context(|| format!("refreshing token at {realm}"))?
+            .json::<TokenResponse>()
+            .await
+            .into_diagnostic()?;
+        let mut token = Token::from_response(response, realm)?;
+        // Endpoints may keep the refresh token valid without handing it out again
+        token
+            .refresh_token
+            .get_or_insert_with(|| refresh_token.to_owned());
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_log::test;
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{body_string_contains, method, path, query_param},
+    };
+
+    #[test(tokio::test)]
+    async fn test_refreshes_expired_token() -> Result<()> {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/auth"))
+            .and(query_param("offline_token", "true"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "token": "first",
+                "expires_in": 5,
+                "refresh_token": "refresh"
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/auth"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .and(body_string_contains("refresh_token=refresh"))
+            .and(body_string_contains("scope=repository%3Arepo%3Apush"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "second",
+                "expires_in": 300
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let auth = Authenticator::new(
+            Client::new(),
+            AuthMethod::Bearer {
+                realm: format!("{}/auth", mock_server.uri()),
+                service: Some("registry".to_string()),
+            },
+            Some(("user".to_string(), "pass".to_string())),
+            vec!["repository:repo:push".to_string()],
+        );
+        // The first token expires within the margin and is refreshed right away
+        assert_eq!(auth.authorization().await?.unwrap(), "Bearer first");
+        assert_eq!(auth.authorization().await?.unwrap(), "Bearer second");
+        assert_eq!(auth.authorization().await?.unwrap(), "Bearer second");
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_renew_rejected_token_once() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let issued = std::sync::atomic::AtomicU32::new(0);
+
+        Mock::given(method("GET"))
+            .and(path("/auth"))
+            .respond_with(move |_: &wiremock::Request| {
+                let n = issued.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "token": format!("token-{n}") }))
+            })
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        let auth = Authenticator::new(
+            Client::new(),
+            AuthMethod::Bearer {
+                realm: format!("{}/auth", mock_server.uri()),
+                service: None,
+            },
+            None,
+            vec!["repository:repo:pull".to_string()],
+        );
+        let rejected = auth.authorization().await?;
+        assert_eq!(rejected.as_deref(), Some("Bearer token-0"));
+        assert!(auth.can_renew());
+        // Two requests rejected with the same token only renew it once
+        let rejected = rejected.unwrap();
+        assert_eq!(auth.renew(&rejected).await?, "Bearer token-1");
+        assert_eq!(auth.renew(&rejected).await?, "Bearer token-1");
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+
+use miette::Result;
+
+/// An authentication challenge from a `WWW-Authenticate` header, see RFC 7235.
+#[derive(Debug, PartialEq)]
+pub struct Challenge {
+    /// The auth scheme in lowercase, e.g. `bearer` or `basic`.
+    pub scheme: String,
+    /// The auth parameters, with names in lowercase and quoted values unescaped.
+    pub params: HashMap<String, String>,
+}
+
+/// Parse all challenges in a `WWW-Authenticate` header value.
+/// Parameters may come in any order, and a `token68` instead of parameters is skipped.
+pub fn parse_challenges(header: &str) -> Result<Vec<Challenge>> {
+    let mut cursor = Cursor { rest: header };
+    let mut challenges = Vec::new();
+    loop {
+        cursor.skip_separators();
+        if cursor.rest.is_empty() {
+            return Ok(challenges);
+        }
+        let scheme = cursor
+            .token()
+            .ok_or_else(|| miette::miette!("expected auth scheme in {header:?}"))?;
+        let mut challenge = Challenge {
+            scheme: scheme.to_ascii_lowercase(),
+            params: HashMap::new(),
+        };
+        cursor.skip_whitespace();
+
+        if cursor.token68() {
+            challenges.push(challenge);
+            continue;
+        }
+        loop {
+            // A token not followed by `=` starts the next challenge
+            let before_param = cursor.rest;
+            let Some(name) = cursor.token() else {
+                break;
+            };
+            cursor.skip_whitespace();
+            if !cursor.eat('=') {
+                cursor.rest = before_param;
+                break;
+            }
+            cursor.skip_whitespace();
+            let value = if cursor.rest.starts_with('"') {
+                cursor
+                    .quoted_string()
+                    .ok_or_else(|| miette::miette!("unterminated quoted string in {header:?}"))?
+            } else {
+                cursor
+                    .token()
+                    .ok_or_else(|| miette::miette!("expected value for {name} in {header:?}"))?
+                    .to_owned()
+            };
+            challenge.params.insert(name.to_ascii_lowercase(), value);
+            cursor.skip_whitespace();
+            if !cursor.eat(',') {
+                break;
+            }
+            cursor.skip_whitespace();
+        }
+        challenges.push(challenge);
+    }
+}
+
+struct Cursor<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start_matches([' ', '\t']);
+    }
+
+    fn skip_separators(&mut self) {
+        self.rest = self.rest.trim_start_matches([' ', '\t', ',']);
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let end = self.rest.find(|c| !pred(c)).unwrap_or(self.rest.len());
+        let (taken, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        taken
+    }
+
+    fn token(&mut self) -> Option<&'a str> {
+        Some(self.take_while(is_tchar)).filter(|token| !token.is_empty())
+    }
+
+    /// Skip a `token68` credential if one follows, e.g. `Negotiate YIIFyw==`.
+    fn token68(&mut self) -> bool {
+        let before = self.rest;
+        let value = self.take_while(|c| c.is_ascii_alphanumeric() || "-._~+/".contains(c));
+        self.take_while(|c| c == '=');
+        let after = self.rest.trim_start_matches([' ', '\t']);
+        // Anything else following, like the value of `name=value`, makes it a parameter
+        if value.is_empty() || !(after.is_empty() || after.starts_with(',')) {
+            self.rest = before;
+            return false;
+        }
+        true
+    }
+
+    fn quoted_string(&mut self) -> Option<String> {
+        let mut chars = self.rest.strip_prefix('"')?.char_indices();
+        let mut value = String::new();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 2..];
+                    return Some(value);
+                }
+                '\\' => value.push(chars.next()?.1),
+                c => value.push(c),
+            }
+        }
+        None
+    }
+}
+
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenge(scheme: &str, params: &[(&str, &str)]) -> Challenge {
+        Challenge {
+            scheme: scheme.to_string(),
+            params: params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_bearer_challenge() {
+        assert_eq!(
+            parse_challenges(
+                r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:user/image:pull""#
+            )
+            .unwrap(),
+            vec![challenge(
+                "bearer",
+                &[
+                    ("realm", "https://ghcr.io/token"),
+                    ("service", "ghcr.io"),
+                    ("scope", "repository:user/image:pull")
+                ]
+            )]
+        );
+        // Any parameter order, unquoted values, whitespace and case
+        assert_eq!(
+            parse_challenges(
+                r#"bearer  Service = registry.example.com , REALM="https://auth.example.com/token""#
+            )
+            .unwrap(),
+            vec![challenge(
+                "bearer",
+                &[
+                    ("service", "registry.example.com"),
+                    ("realm", "https://auth.example.com/token")
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_multiple_challenges() {
+        assert_eq!(
+            parse_challenges(
+                r#"Basic realm="Artifactory \"Realm\"", Bearer realm="https://example.com/token", service=example"#
+            )
+            .unwrap(),
+            vec![
+                challenge("basic", &[("realm", r#"Artifactory "Realm""#)]),
+                challenge(
+                    "bearer",
+                    &[("realm", "https://example.com/token"), ("service", "example")]
+                ),
+            ]
+        );
+        assert_eq!(
+            parse_challenges("Negotiate, Newauth abc==, Basic").unwrap(),
+            vec![
+                challenge("negotiate", &[]),
+                challenge("newauth", &[]),
+                challenge("basic", &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_challenges() {
+        assert!(parse_challenges(r#"Bearer realm="unterminated"#).is_err());
+        assert!(parse_challenges("Bearer realm=\"x\", =").is_err());
+        assert!(parse_challenges("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,158 @@
+use std::time::{Duration, SystemTime};
+
+use miette::Result;
+use reqwest::{Response, StatusCode};
+
+/// How often requests are attempted unless configured otherwise.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 4;
+
+/// When and how often requests failing with transient errors are sent again.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts per request including the first one, at least 1.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further retry.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn with_max_attempts(max_attempts: Option<u32>) -> Self {
+        Self {
+            max_attempts: max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
+            ..Default::default()
+        }
+    }
+
+    /// How long to wait before sending a request again after its `attempt`-th attempt
+    /// resulted in `result`, or `None` if it should not be retried.
+    /// Errors without a response, e.g. a reset connection, are assumed to be transient.
+    /// A `Retry-After` longer than the maximum backoff is cut short.
+    pub fn delay(&self, attempt: u32, result: &Result<Response>) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        match result {
+            Ok(response) if is_transient(response.status()) => {
+                Some(retry_after(response).map_or_else(
+                    || self.backoff(attempt),
+                    |delay| delay.min(self.max_backoff),
+                ))
+            }
+            Ok(_) => None,
+            Err(_) => Some(self.backoff(attempt)),
+        }
+    }
+
+    /// The exponential backoff after the `attempt`-th attempt.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+/// Whether a request failing with the status may succeed later. `501 Not Implemented` is
+/// how registries reject unsupported operations, so it is not retried.
+pub fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || (status.is_server_error() && status != StatusCode::NOT_IMPLEMENTED)
+}
+
+/// A request failed transiently, but couldn't be sent again because its body was streamed.
+/// Whoever provides the stream may start over.
+#[derive(Debug)]
+pub struct TransientError(pub String);
+
+impl std::fmt::Display for TransientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+impl miette::Diagnostic for TransientError {}
+
+/// The delay requested by the `Retry-After` header, given in seconds or as a date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?;
+    parse_retry_after(value, SystemTime::now())
+}
+
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Describe a failed attempt for logging.
+pub fn describe_failure(result: &Result<Response>) -> String {
+    match result {
+        Ok(response) => format!("{} returned {}", response.url(), response.status()),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(4), Duration::from_secs(5));
+        assert_eq!(policy.backoff(40), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        // A date in the past means right away
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_transient(StatusCode::BAD_GATEWAY));
+        assert!(is_transient(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_transient(StatusCode::NOT_IMPLEMENTED));
+        assert!(!is_transient(StatusCode::NOT_FOUND));
+    }
+}
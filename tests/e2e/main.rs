@@ -38,8 +38,14 @@ async fn simple_build_to_registry() -> Result<(), Box<dyn std::error::Error>> {
     let host_port = container.get_host_port_ipv4(5000).await?;
     let host = format!("{host_name}:{host_port}");
 
+    // Build from a copy of the recipe, so the lockfile klt writes next to it doesn't end up
+    // in the source tree and carry over to the next run
+    let recipe_dir = TempDir::new()?;
+    let recipe_path = recipe_dir.path().join("simple.toml");
+    fs::copy("tests/e2e/simple.toml", &recipe_path)?;
+
     let result = Command::new(env!("CARGO_BIN_EXE_klt"))
-        .arg("tests/e2e/simple.toml")
+        .arg(&recipe_path)
         .env("REGISTRY", &host)
         // SSL_CERT_FILE is a convention for specifying the path to a CA root certificate
         // rustls picks it up automatically via openssl-probe
@@ -51,7 +57,7 @@ async fn simple_build_to_registry() -> Result<(), Box<dyn std::error::Error>> {
 
     // Pull the pushed image to verify it was successfully pushed
     let pull_result = Command::new("docker")
-        .args(&["pull", &format!("{}/simple:latest", host)])
+        .args(["pull", &format!("{}/simple:latest", host)])
         .env("SSL_CERT_FILE", cert_path.to_str().unwrap())
         .spawn()?
         .wait_with_output()
@@ -60,7 +66,7 @@ async fn simple_build_to_registry() -> Result<(), Box<dyn std::error::Error>> {
 
     // Cleanup
     Command::new("docker")
-        .args(&["rmi", &format!("{}/simple:latest", host)])
+        .args(["rmi", &format!("{}/simple:latest", host)])
         .spawn()?
         .wait_with_output()
         .await?;